@@ -0,0 +1,371 @@
+use crate::glob::glob_match;
+use anyhow::{Result, bail};
+use oci_spec::runtime::Spec;
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Prefix of annotations which control the interceptor.
+pub(crate) const ANNOTATION_PREFIX: &str = "org.picoctf.oci-interceptor.";
+
+/// Names of the annotations recording how a config was modified.
+const VERSION_ANNOTATION: &str = "version";
+const POLICY_HASH_ANNOTATION: &str = "policy-hash";
+const MODIFICATIONS_ANNOTATION: &str = "modifications";
+const CONFIG_HASH_ANNOTATION: &str = "config-hash";
+
+/// Name of the annotation recording the path of a config which is not the bundle's
+/// `config.json` (e.g. given with crun's `--config` option).
+pub(crate) const CONFIG_ANNOTATION: &str = "config";
+
+/// Names of the annotations which containers may use to vary the interceptor's behavior, if
+/// permitted.
+const OPTION_ANNOTATIONS: &[&str] = &["skip", "profile"];
+
+/// Permits containers to set an interceptor annotation to values matching a pattern.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AnnotationPermit {
+    name: String,
+    pattern: String,
+}
+
+/// Parses an annotation permit in `NAME` or `NAME=PATTERN` format.
+pub(crate) fn parse_annotation_permit(value: &str) -> Result<AnnotationPermit, anyhow::Error> {
+    let (name, pattern) = value.split_once('=').unwrap_or((value, "*"));
+    if !OPTION_ANNOTATIONS.contains(&name) {
+        bail!(
+            "annotation names must be one of: {}",
+            OPTION_ANNOTATIONS.join(", ")
+        );
+    }
+    Ok(AnnotationPermit {
+        name: String::from(name),
+        pattern: String::from(pattern),
+    })
+}
+
+/// Removes interceptor annotations which the container is not permitted to set, so that they
+/// cannot be used to opt out of policies or to match selectors, and `skip` annotations which are
+/// neither `true` nor `false`.
+///
+/// Returns a description of each annotation which was removed.
+pub(crate) fn sanitize_annotations(spec: &mut Spec, permits: &[AnnotationPermit]) -> Vec<String> {
+    let mut changes = Vec::new();
+    let Some(annotations) = spec.annotations() else {
+        return changes;
+    };
+    let mut annotations = annotations.clone();
+    annotations.retain(|key, value| {
+        let Some(name) = key.strip_prefix(ANNOTATION_PREFIX) else {
+            return true;
+        };
+        let permitted = permits
+            .iter()
+            .any(|p| p.name == name && glob_match(&p.pattern, value));
+        if !permitted {
+            changes.push(format!("annotations: removed non-permitted {key}={value}"));
+            return false;
+        }
+        if name == "skip" && value.parse::<bool>().is_err() {
+            changes.push(format!(
+                "annotations: removed invalid {key}={value} (must be true or false)"
+            ));
+            return false;
+        }
+        true
+    });
+    changes.sort();
+    spec.set_annotations(Some(annotations));
+    changes
+}
+
+/// Returns the interceptor annotations which containers are permitted to set, with the patterns
+/// their values must match.
+pub(crate) fn permitted_annotations(permits: &[AnnotationPermit]) -> BTreeMap<String, Vec<String>> {
+    let mut permitted: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for permit in permits {
+        permitted
+            .entry(format!("{ANNOTATION_PREFIX}{}", permit.name))
+            .or_default()
+            .push(permit.pattern.clone());
+    }
+    permitted
+}
+
+/// Returns whether the container has opted out of all modifications.
+///
+/// Values other than `true` are ignored, so that the annotation cannot be used to prevent the
+/// container from starting.
+pub(crate) fn skip_requested(spec: &Spec) -> bool {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get(&format!("{ANNOTATION_PREFIX}skip")))
+        .is_some_and(|value| value == "true")
+}
+
+/// The 64-bit FNV-1a hash of a sequence of byte strings, each terminated by a zero byte.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter().chain([&0]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// Returns a fingerprint of the interceptor's configuration, using the 64-bit FNV-1a hash of its
+/// arguments and of the policy files they refer to (e.g. seccomp profiles). The files in
+/// directories of policy files (e.g. CDI spec directories) are hashed in name order, and files
+/// which cannot be read are hashed as empty.
+///
+/// This identifies a configuration, but is not a cryptographic hash.
+pub(crate) fn policy_hash(args: &[String], policy_paths: &[&Path]) -> String {
+    let mut hash = Fnv1a::new();
+    for arg in args {
+        hash.write(arg.as_bytes());
+    }
+    for path in policy_paths {
+        let mut files: Vec<PathBuf> = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect(),
+            Err(_) => vec![path.to_path_buf()],
+        };
+        files.sort();
+        for file in files {
+            hash.write(file.as_os_str().as_bytes());
+            hash.write(&fs::read(&file).unwrap_or_default());
+        }
+    }
+    hash.finish()
+}
+
+/// Returns a fingerprint of a config, which must not contain a marker.
+fn config_hash(spec: &Spec) -> Result<String> {
+    // Values sort their keys, unlike the config's own maps
+    let mut config = serde_json::to_value(spec)?;
+    // Taking the marker from a config without other annotations leaves them empty
+    if config["annotations"] == serde_json::json!({})
+        && let Some(config) = config.as_object_mut()
+    {
+        config.remove("annotations");
+    }
+    let mut hash = Fnv1a::new();
+    hash.write(config.to_string().as_bytes());
+    Ok(hash.finish())
+}
+
+/// Records which interceptor version and configuration modified a config, and how.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Marker {
+    pub(crate) version: String,
+    pub(crate) policy_hash: String,
+    pub(crate) modifications: String,
+    /// The fingerprint of the modified config, so that a config changed since it was modified
+    /// (e.g. one with a copied marker) is not mistaken for an intercepted one.
+    config_hash: String,
+}
+
+impl Marker {
+    /// Creates a marker for a modified config, which must not contain a marker.
+    pub(crate) fn new(policy_hash: &str, modifications: &[&str], spec: &Spec) -> Result<Self> {
+        let mut names: Vec<&str> = Vec::new();
+        for name in modifications {
+            if !names.contains(name) {
+                names.push(name);
+            }
+        }
+        Ok(Marker {
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            policy_hash: String::from(policy_hash),
+            modifications: names.join(","),
+            config_hash: config_hash(spec)?,
+        })
+    }
+
+    /// Returns whether the marker was recorded by this version and configuration of the
+    /// interceptor in a config, which must not contain the marker itself.
+    pub(crate) fn matches(&self, policy_hash: &str, spec: &Spec) -> Result<bool> {
+        Ok(self.version == env!("CARGO_PKG_VERSION")
+            && self.policy_hash == policy_hash
+            && self.config_hash == config_hash(spec)?)
+    }
+}
+
+/// Removes and returns the marker recorded by a previous invocation of the interceptor, if any.
+pub(crate) fn take_marker(spec: &mut Spec) -> Option<Marker> {
+    let mut annotations = spec.annotations().clone()?;
+    let mut take = |name: &str| annotations.remove(&format!("{ANNOTATION_PREFIX}{name}"));
+    let version = take(VERSION_ANNOTATION);
+    let policy_hash = take(POLICY_HASH_ANNOTATION);
+    let modifications = take(MODIFICATIONS_ANNOTATION);
+    let config_hash = take(CONFIG_HASH_ANNOTATION);
+    spec.set_annotations(Some(annotations));
+    Some(Marker {
+        version: version?,
+        policy_hash: policy_hash?,
+        modifications: modifications.unwrap_or_default(),
+        config_hash: config_hash?,
+    })
+}
+
+/// Records a marker in the config's annotations.
+pub(crate) fn record_marker(spec: &mut Spec, marker: &Marker) {
+    let mut annotations = spec.annotations().clone().unwrap_or_default();
+    for (name, value) in [
+        (VERSION_ANNOTATION, &marker.version),
+        (POLICY_HASH_ANNOTATION, &marker.policy_hash),
+        (MODIFICATIONS_ANNOTATION, &marker.modifications),
+        (CONFIG_HASH_ANNOTATION, &marker.config_hash),
+    ] {
+        annotations.insert(format!("{ANNOTATION_PREFIX}{name}"), value.clone());
+    }
+    spec.set_annotations(Some(annotations));
+}
+
+/// Records the path of the container's config in its annotations, so that it can be found by
+/// later runtime calls for the container.
+pub(crate) fn record_config_path(spec: &mut Spec, config_path: &Path) {
+    let mut annotations = spec.annotations().clone().unwrap_or_default();
+    annotations.insert(
+        format!("{ANNOTATION_PREFIX}{CONFIG_ANNOTATION}"),
+        config_path.to_string_lossy().into_owned(),
+    );
+    spec.set_annotations(Some(annotations));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn container(annotations: &[(&str, &str)]) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_annotations(Some(
+            annotations
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect::<HashMap<String, String>>(),
+        ));
+        spec
+    }
+
+    fn permits(values: &[&str]) -> Vec<AnnotationPermit> {
+        values
+            .iter()
+            .map(|v| parse_annotation_permit(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_annotation_permits() {
+        assert_eq!(
+            parse_annotation_permit("skip").unwrap(),
+            AnnotationPermit {
+                name: String::from("skip"),
+                pattern: String::from("*"),
+            }
+        );
+        assert_eq!(
+            parse_annotation_permit("profile=strict").unwrap().pattern,
+            "strict"
+        );
+        assert!(parse_annotation_permit("version").is_err());
+    }
+
+    #[test]
+    fn removes_non_permitted_annotations() {
+        let mut spec = container(&[
+            ("org.picoctf.oci-interceptor.skip", "true"),
+            ("org.picoctf.oci-interceptor.profile", "strict"),
+            ("org.example.other", "value"),
+        ]);
+        let changes = sanitize_annotations(&mut spec, &permits(&["profile=strict"]));
+        assert_eq!(
+            changes,
+            ["annotations: removed non-permitted org.picoctf.oci-interceptor.skip=true"]
+        );
+        let annotations = spec.annotations().as_ref().unwrap();
+        assert_eq!(annotations.len(), 2);
+        assert!(!skip_requested(&spec));
+
+        let mut spec = container(&[("org.picoctf.oci-interceptor.profile", "relaxed")]);
+        sanitize_annotations(&mut spec, &permits(&["profile=strict"]));
+        assert!(spec.annotations().as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hashes_policies() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        let hash = policy_hash(&args(&["--oi-env", "A=1"]), &[]);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, policy_hash(&args(&["--oi-env", "A=1"]), &[]));
+        assert_ne!(hash, policy_hash(&args(&["--oi-env", "A=2"]), &[]));
+        assert_ne!(hash, policy_hash(&args(&["--oi-envA", "=1"]), &[]));
+
+        // The contents of policy files and directories are included
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("profile.json");
+        let hooks_dir = dir.path().join("hooks.d");
+        fs::create_dir(&hooks_dir).unwrap();
+        fs::write(&profile, "{}").unwrap();
+        let paths = [profile.as_path(), hooks_dir.as_path()];
+        let hash = policy_hash(&[], &paths);
+        assert_eq!(hash, policy_hash(&[], &paths));
+        fs::write(hooks_dir.join("hook.json"), "{}").unwrap();
+        let with_hook = policy_hash(&[], &paths);
+        assert_ne!(hash, with_hook);
+        fs::write(&profile, r#"{"defaultAction": "SCMP_ACT_ALLOW"}"#).unwrap();
+        assert_ne!(with_hook, policy_hash(&[], &paths));
+    }
+
+    #[test]
+    fn records_and_takes_markers() {
+        let mut spec = container(&[("org.example.other", "value")]);
+        assert_eq!(take_marker(&mut spec), None);
+        let marker = Marker::new("0123456789abcdef", &["env", "seccomp", "env"], &spec).unwrap();
+        assert!(marker.matches("0123456789abcdef", &spec).unwrap());
+        assert!(!marker.matches("fedcba9876543210", &spec).unwrap());
+        record_marker(&mut spec, &marker);
+        assert_eq!(
+            spec.annotations().as_ref().unwrap()["org.picoctf.oci-interceptor.modifications"],
+            "env,seccomp"
+        );
+        assert_eq!(take_marker(&mut spec), Some(marker.clone()));
+        assert_eq!(spec.annotations().as_ref().unwrap().len(), 1);
+
+        // Markers do not match configs changed since they were recorded
+        spec.set_hostname(Some(String::from("other")));
+        assert!(!marker.matches("0123456789abcdef", &spec).unwrap());
+    }
+
+    #[test]
+    fn honors_permitted_skip() {
+        let mut spec = container(&[("org.picoctf.oci-interceptor.skip", "true")]);
+        sanitize_annotations(&mut spec, &permits(&["skip"]));
+        assert!(skip_requested(&spec));
+        let mut spec = container(&[("org.picoctf.oci-interceptor.skip", "yes")]);
+        let changes = sanitize_annotations(&mut spec, &permits(&["skip"]));
+        assert_eq!(
+            changes,
+            [
+                "annotations: removed invalid org.picoctf.oci-interceptor.skip=yes (must be true or false)"
+            ]
+        );
+        assert!(!skip_requested(&spec));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{
+    cpuset::release_cpuset,
+    hooks::{HookStage, add_hook},
+};
+use anyhow::{Context, Result};
+use oci_spec::runtime::{HookBuilder, Spec, State};
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Name of the subcommand used to invoke the interceptor as an OCI hook.
+pub(crate) const HOOK_SUBCOMMAND: &str = "oi-hook";
+
+/// An action performed by the interceptor when invoked as an OCI hook.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum HookAction {
+    /// Writes the container's PID to `<pid_dir>/<container ID>.pid`.
+    RecordPid { pid_dir: PathBuf },
+    /// Removes the PID file written by `RecordPid`.
+    RemovePid { pid_dir: PathBuf },
+    /// Frees the container's cpuset allocation.
+    ReleaseCpuset { state_path: PathBuf },
+}
+
+impl HookAction {
+    /// Returns the arguments which invoke this action, following the subcommand.
+    fn args(&self) -> Vec<String> {
+        let (action, option, path) = match self {
+            HookAction::RecordPid { pid_dir } => ("record-pid", "--pid-dir", pid_dir),
+            HookAction::RemovePid { pid_dir } => ("remove-pid", "--pid-dir", pid_dir),
+            HookAction::ReleaseCpuset { state_path } => {
+                ("release-cpuset", "--cpuset-state", state_path)
+            }
+        };
+        vec![
+            String::from(action),
+            String::from(option),
+            path.to_string_lossy().into_owned(),
+        ]
+    }
+}
+
+/// Reads the container state passed to hooks on stdin.
+pub(crate) fn read_state(mut reader: impl Read) -> Result<State> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
+    serde_json::from_str(&contents).with_context(|| "Unable to parse container state")
+}
+
+fn pid_file(pid_dir: &Path, state: &State) -> PathBuf {
+    pid_dir.join(format!("{}.pid", state.id()))
+}
+
+/// Performs a built-in hook action for a container.
+pub(crate) fn run_hook_action(action: &HookAction, state: &State) -> Result<()> {
+    match action {
+        HookAction::RecordPid { pid_dir } => {
+            let pid = state
+                .pid()
+                .with_context(|| "Container state does not include a PID")?;
+            fs::create_dir_all(pid_dir)?;
+            fs::write(pid_file(pid_dir, state), format!("{pid}\n"))
+                .with_context(|| "Unable to write PID file")?;
+        }
+        HookAction::RemovePid { pid_dir } => match fs::remove_file(pid_file(pid_dir, state)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).with_context(|| "Unable to remove PID file");
+            }
+            _ => {}
+        },
+        HookAction::ReleaseCpuset { state_path } => release_cpuset(state.id(), state_path)?,
+    }
+    Ok(())
+}
+
+/// Adds a hook which invokes the interceptor binary itself to perform a built-in action.
+///
+/// Returns a description of the hook which was added.
+pub(crate) fn add_builtin_hook(
+    spec: &mut Spec,
+    stage: HookStage,
+    action: &HookAction,
+) -> Result<String> {
+    let path = env::current_exe().with_context(|| "Unable to determine interceptor path")?;
+    let mut args = vec![
+        String::from(clap::crate_name!()),
+        String::from(HOOK_SUBCOMMAND),
+    ];
+    args.extend(action.args());
+    let hook = HookBuilder::default().path(path).args(args).build()?;
+    add_hook(spec, stage, hook);
+    Ok(format!(
+        "hooks: added built-in {} hook {}",
+        stage.name(),
+        action.args()[0]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::StateBuilder;
+
+    fn state(id: &str, pid: i32) -> State {
+        StateBuilder::default()
+            .version("1.2.0")
+            .id(id)
+            .pid(pid)
+            .bundle("/tmp/bundle")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_container_state() {
+        let state = read_state(
+            r#"{"ociVersion": "1.2.0", "id": "cid", "status": "created", "pid": 4242, "bundle": "/tmp/bundle"}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(state.id(), "cid");
+        assert_eq!(state.pid(), &Some(4242));
+        assert!(read_state("{".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn records_and_removes_pids() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_dir = dir.path().join("pids");
+        let state = state("cid", 4242);
+        run_hook_action(
+            &HookAction::RecordPid {
+                pid_dir: pid_dir.clone(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(pid_dir.join("cid.pid")).unwrap(),
+            "4242\n"
+        );
+        let remove = HookAction::RemovePid {
+            pid_dir: pid_dir.clone(),
+        };
+        run_hook_action(&remove, &state).unwrap();
+        assert!(!pid_dir.join("cid.pid").exists());
+        run_hook_action(&remove, &state).unwrap();
+    }
+
+    #[test]
+    fn adds_builtin_hooks() {
+        let mut spec = Spec::default();
+        let action = HookAction::ReleaseCpuset {
+            state_path: PathBuf::from("/var/lib/oci-interceptor/cpusets.json"),
+        };
+        add_builtin_hook(&mut spec, HookStage::Poststop, &action).unwrap();
+        let hooks = spec.hooks().as_ref().unwrap().poststop().clone().unwrap();
+        assert_eq!(hooks[0].path(), &env::current_exe().unwrap());
+        assert_eq!(
+            hooks[0].args().as_deref().unwrap()[1..],
+            [
+                "oi-hook",
+                "release-cpuset",
+                "--cpuset-state",
+                "/var/lib/oci-interceptor/cpusets.json"
+            ]
+        );
+    }
+}
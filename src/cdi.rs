@@ -0,0 +1,448 @@
+use crate::{
+    devices::host_device,
+    env_vars::{EnvVarOverride, modify_env_vars, parse_env_var},
+    hooks::{HookStage, add_hook},
+};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{
+    HookBuilder, LinuxDeviceBuilder, LinuxDeviceCgroupBuilder, LinuxDeviceType, MountBuilder, Spec,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Prefix of annotations requesting CDI devices.
+const CDI_ANNOTATION_PREFIX: &str = "cdi.k8s.io/";
+
+/// A Container Device Interface spec file.
+///
+/// See <https://github.com/cncf-tags/container-device-interface/blob/main/SPEC.md>.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CdiSpec {
+    kind: String,
+    devices: Vec<CdiDevice>,
+    #[serde(default)]
+    container_edits: ContainerEdits,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CdiDevice {
+    name: String,
+    container_edits: ContainerEdits,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerEdits {
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    device_nodes: Vec<DeviceNode>,
+    #[serde(default)]
+    hooks: Vec<CdiHook>,
+    #[serde(default)]
+    mounts: Vec<CdiMount>,
+    #[serde(default)]
+    additional_gids: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceNode {
+    path: PathBuf,
+    host_path: Option<PathBuf>,
+    #[serde(rename = "type")]
+    typ: Option<String>,
+    major: Option<i64>,
+    minor: Option<i64>,
+    file_mode: Option<u32>,
+    permissions: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CdiHook {
+    hook_name: String,
+    path: PathBuf,
+    args: Option<Vec<String>>,
+    env: Option<Vec<String>>,
+    timeout: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CdiMount {
+    host_path: PathBuf,
+    container_path: PathBuf,
+    #[serde(rename = "type")]
+    typ: Option<String>,
+    options: Option<Vec<String>>,
+}
+
+fn load_cdi_spec(path: &Path) -> Result<Option<CdiSpec>> {
+    let spec = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&fs::read_to_string(path)?)?,
+        Some("yaml" | "yml") => serde_norway::from_str(&fs::read_to_string(path)?)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(spec))
+}
+
+/// Loads the CDI spec files (`*.json`, `*.yaml` and `*.yml`) in a list of directories.
+///
+/// Missing directories are ignored, and invalid spec files are skipped.
+///
+/// Returns the specs, and a warning for each spec file which was skipped.
+fn load_cdi_specs(dirs: &[PathBuf]) -> (Vec<CdiSpec>, Vec<String>) {
+    let mut specs = Vec::new();
+    let mut warnings = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+        paths.sort();
+        for path in paths {
+            match load_cdi_spec(&path)
+                .with_context(|| format!("Skipped invalid CDI spec {}", path.display()))
+            {
+                Ok(Some(spec)) => specs.push(spec),
+                Ok(None) => {}
+                Err(err) => warnings.push(format!("{err:#}")),
+            }
+        }
+    }
+    (specs, warnings)
+}
+
+/// Returns the fully-qualified names (`vendor.com/class=name`) of the CDI devices requested by a
+/// container's annotations.
+fn requested_devices(spec: &Spec) -> Vec<String> {
+    let mut annotations: Vec<(&String, &String)> = spec
+        .annotations()
+        .iter()
+        .flatten()
+        .filter(|(key, _)| key.starts_with(CDI_ANNOTATION_PREFIX))
+        .collect();
+    annotations.sort();
+    let mut devices: Vec<String> = Vec::new();
+    for (_, value) in annotations {
+        for device in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            if !devices.iter().any(|d| d == device) {
+                devices.push(String::from(device));
+            }
+        }
+    }
+    devices
+}
+
+/// Applies a set of CDI container edits.
+fn apply_container_edits(spec: &mut Spec, edits: &ContainerEdits) -> Result<()> {
+    if !edits.env.is_empty() {
+        let vars = edits
+            .env
+            .iter()
+            .map(|var| parse_env_var(var).map(|var| EnvVarOverride::new(&var, true)))
+            .collect::<Result<Vec<EnvVarOverride>>>()?;
+        modify_env_vars(spec, &vars);
+    }
+
+    if let Some(linux) = spec.linux()
+        && !edits.device_nodes.is_empty()
+    {
+        let mut linux = linux.clone();
+        let mut devices = linux.devices().clone().unwrap_or_default();
+        let mut resources = linux.resources().clone().unwrap_or_default();
+        let mut rules = resources.devices().clone().unwrap_or_default();
+        for node in &edits.device_nodes {
+            let host_path = node.host_path.as_ref().unwrap_or(&node.path);
+            let host = match (&node.typ, node.major, node.minor) {
+                (Some(_), Some(_), Some(_)) => None,
+                _ => Some(host_device(host_path)?),
+            };
+            let typ = match &node.typ {
+                Some(typ) => typ
+                    .parse()
+                    .with_context(|| format!("Invalid CDI device node type {typ}"))?,
+                None => host.as_ref().map_or(LinuxDeviceType::C, |h| h.typ()),
+            };
+            let major = node.major.or(host.as_ref().map(|h| h.major())).unwrap_or(0);
+            let minor = node.minor.or(host.as_ref().map(|h| h.minor())).unwrap_or(0);
+            let mut device = LinuxDeviceBuilder::default()
+                .path(&node.path)
+                .typ(typ)
+                .major(major)
+                .minor(minor)
+                .build()?;
+            device.set_file_mode(node.file_mode.or(host.as_ref().and_then(|h| h.file_mode())));
+            device.set_uid(node.uid.or(host.as_ref().and_then(|h| h.uid())));
+            device.set_gid(node.gid.or(host.as_ref().and_then(|h| h.gid())));
+            devices.retain(|d| d.path() != device.path());
+            devices.push(device);
+            let rule = LinuxDeviceCgroupBuilder::default()
+                .allow(true)
+                .typ(typ)
+                .major(major)
+                .minor(minor)
+                .access(node.permissions.as_deref().unwrap_or("rwm"))
+                .build()?;
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+        linux.set_devices(Some(devices));
+        resources.set_devices(Some(rules));
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+    }
+
+    if !edits.mounts.is_empty() {
+        let mut mounts = spec.mounts().clone().unwrap_or_default();
+        for cdi_mount in &edits.mounts {
+            let mut mount = MountBuilder::default()
+                .destination(&cdi_mount.container_path)
+                .source(&cdi_mount.host_path)
+                .build()?;
+            mount.set_typ(cdi_mount.typ.clone());
+            mount.set_options(cdi_mount.options.clone());
+            mounts.retain(|m| m.destination() != mount.destination());
+            mounts.push(mount);
+        }
+        spec.set_mounts(Some(mounts));
+    }
+
+    for cdi_hook in &edits.hooks {
+        let stage = HookStage::parse(&cdi_hook.hook_name)?;
+        let mut hook = HookBuilder::default().path(&cdi_hook.path).build()?;
+        hook.set_args(cdi_hook.args.clone());
+        hook.set_env(cdi_hook.env.clone());
+        hook.set_timeout(cdi_hook.timeout);
+        add_hook(spec, stage, hook);
+    }
+
+    if let Some(process) = spec.process()
+        && !edits.additional_gids.is_empty()
+    {
+        let mut process = process.clone();
+        let mut user = process.user().clone();
+        let mut gids = user.additional_gids().clone().unwrap_or_default();
+        for gid in &edits.additional_gids {
+            if !gids.contains(gid) {
+                gids.push(*gid);
+            }
+        }
+        user.set_additional_gids(Some(gids));
+        process.set_user(user);
+        spec.set_process(Some(process));
+    }
+    Ok(())
+}
+
+/// Applies the container edits of the CDI devices requested by the container's annotations
+/// (`cdi.k8s.io/<name>=vendor.com/class=device`).
+///
+/// Spec files are loaded from each directory in order, with devices in later directories taking
+/// precedence. Invalid spec files are skipped. Fails if a requested device cannot be found.
+///
+/// Returns a description of each device which was applied, and a warning for each spec file which
+/// was skipped.
+pub(crate) fn modify_cdi_devices(
+    spec: &mut Spec,
+    spec_dirs: &[PathBuf],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let requested = requested_devices(spec);
+    if requested.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let (cdi_specs, warnings) = load_cdi_specs(spec_dirs);
+    let mut applied_specs: Vec<usize> = Vec::new();
+    let mut changes = Vec::new();
+    for qualified_name in requested {
+        let Some((kind, name)) = qualified_name.split_once('=') else {
+            bail!("CDI device names must be in vendor.com/class=name format");
+        };
+        let Some((index, cdi_spec, device)) = cdi_specs
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, s)| s.kind == kind)
+            .find_map(|(i, s)| s.devices.iter().find(|d| d.name == name).map(|d| (i, s, d)))
+        else {
+            bail!("Unable to find CDI device {qualified_name}");
+        };
+        if !applied_specs.contains(&index) {
+            apply_container_edits(spec, &cdi_spec.container_edits)?;
+            applied_specs.push(index);
+        }
+        apply_container_edits(spec, &device.container_edits)?;
+        changes.push(format!("cdi: applied {qualified_name}"));
+    }
+    Ok((changes, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    const FUSE_SPEC: &str = r#"
+cdiVersion: 0.6.0
+kind: example.com/fuse
+containerEdits:
+  env:
+    - FUSE_ENABLED=1
+devices:
+  - name: fuse
+    containerEdits:
+      deviceNodes:
+        - path: /dev/fuse
+          hostPath: /dev/null
+          permissions: rw
+      mounts:
+        - hostPath: /usr/lib/fuse
+          containerPath: /usr/lib/fuse
+          options: [ro, bind]
+      hooks:
+        - hookName: createContainer
+          path: /usr/bin/fuse-hook
+          args: [fuse-hook, setup]
+"#;
+
+    const GPU_SPEC: &str = r#"{
+        "cdiVersion": "0.6.0",
+        "kind": "example.com/gpu",
+        "devices": [
+            {
+                "name": "gpu0",
+                "containerEdits": {
+                    "deviceNodes": [{"path": "/dev/gpu0", "type": "c", "major": 195, "minor": 0}],
+                    "additionalGids": [44]
+                }
+            }
+        ]
+    }"#;
+
+    fn spec_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("fuse.yaml"), FUSE_SPEC).unwrap();
+        fs::write(dir.path().join("gpu.json"), GPU_SPEC).unwrap();
+        dir
+    }
+
+    fn container(annotations: &[(&str, &str)]) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_annotations(Some(
+            annotations
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect::<HashMap<String, String>>(),
+        ));
+        spec
+    }
+
+    #[test]
+    fn finds_requested_devices() {
+        let spec = container(&[
+            ("cdi.k8s.io/b", "example.com/gpu=gpu0"),
+            (
+                "cdi.k8s.io/a",
+                "example.com/fuse=fuse, example.com/gpu=gpu0",
+            ),
+            ("org.example.other", "example.com/gpu=gpu1"),
+        ]);
+        assert_eq!(
+            requested_devices(&spec),
+            ["example.com/fuse=fuse", "example.com/gpu=gpu0"]
+        );
+    }
+
+    #[test]
+    fn applies_container_edits() {
+        let dir = spec_dir();
+        let dirs = vec![dir.path().to_path_buf()];
+        let mut spec = container(&[(
+            "cdi.k8s.io/devices",
+            "example.com/fuse=fuse,example.com/gpu=gpu0",
+        )]);
+        let (changes, warnings) = modify_cdi_devices(&mut spec, &dirs).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(
+            changes,
+            [
+                "cdi: applied example.com/fuse=fuse",
+                "cdi: applied example.com/gpu=gpu0"
+            ]
+        );
+
+        let process = spec.process().as_ref().unwrap();
+        assert!(
+            process
+                .env()
+                .as_ref()
+                .unwrap()
+                .contains(&String::from("FUSE_ENABLED=1"))
+        );
+        assert_eq!(process.user().additional_gids().as_deref(), Some(&[44][..]));
+
+        let linux = spec.linux().as_ref().unwrap();
+        let devices = linux.devices().as_ref().unwrap();
+        assert_eq!(devices[0].path(), &PathBuf::from("/dev/fuse"));
+        assert_eq!((devices[0].major(), devices[0].minor()), (1, 3));
+        assert_eq!((devices[1].major(), devices[1].minor()), (195, 0));
+        let rules = linux
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .as_ref()
+            .unwrap();
+        assert_eq!(rules[0].to_string(), "c 1:3 rw");
+
+        let mounts = spec.mounts().as_ref().unwrap();
+        assert!(
+            mounts
+                .iter()
+                .any(|m| m.destination() == &PathBuf::from("/usr/lib/fuse"))
+        );
+        let hooks = spec
+            .hooks()
+            .as_ref()
+            .unwrap()
+            .create_container()
+            .as_ref()
+            .unwrap();
+        assert_eq!(hooks[0].path(), &PathBuf::from("/usr/bin/fuse-hook"));
+    }
+
+    #[test]
+    fn rejects_unknown_devices() {
+        let dir = spec_dir();
+        let dirs = vec![dir.path().to_path_buf()];
+        let mut spec = container(&[("cdi.k8s.io/devices", "example.com/gpu=gpu1")]);
+        assert!(modify_cdi_devices(&mut spec, &dirs).is_err());
+        let mut spec = container(&[]);
+        assert!(modify_cdi_devices(&mut spec, &dirs).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn skips_invalid_specs() {
+        let dir = spec_dir();
+        fs::write(dir.path().join("broken.json"), "{").unwrap();
+        let dirs = vec![dir.path().to_path_buf()];
+        let mut spec = container(&[("cdi.k8s.io/devices", "example.com/fuse=fuse")]);
+        let (changes, warnings) = modify_cdi_devices(&mut spec, &dirs).unwrap();
+        assert_eq!(changes, ["cdi: applied example.com/fuse=fuse"]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with(&format!(
+            "Skipped invalid CDI spec {}",
+            dir.path().join("broken.json").display()
+        )));
+    }
+}
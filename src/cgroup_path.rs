@@ -0,0 +1,148 @@
+use crate::selector::{Selected, parse_selected, select};
+use anyhow::{Result, bail};
+use oci_spec::runtime::Spec;
+use std::path::{Component, Path, PathBuf};
+
+/// Parses a cgroup parent, which is either a systemd slice (e.g. `challenges.slice`) or an
+/// absolute cgroupfs path (e.g. `/challenges`), optionally followed by a selector.
+pub(crate) fn parse_cgroup_parent(value: &str) -> Result<Selected<String>, anyhow::Error> {
+    let parent = parse_selected(value)?;
+    if is_slice(&parent.value) {
+        return Ok(parent);
+    }
+    let path = Path::new(&parent.value);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        bail!("cgroup parents must be systemd slices (NAME.slice) or absolute paths");
+    }
+    Ok(parent)
+}
+
+fn is_slice(parent: &str) -> bool {
+    parent.len() > ".slice".len() && parent.ends_with(".slice") && !parent.contains(['/', ':'])
+}
+
+/// Places the container's cgroup under a parent, so that limits on the parent apply to all
+/// containers placed under it.
+///
+/// The systemd form of `linux.cgroupsPath` (`slice:prefix:name`) has its slice replaced, while
+/// the cgroupfs form is moved under the parent path, keeping only its final component.
+/// Containers without a cgroups path are left to the runtime's default.
+///
+/// Returns a description of the change which was made, if any.
+pub(crate) fn modify_cgroups_path(
+    spec: &mut Spec,
+    parents: &[Selected<String>],
+) -> Result<Option<String>> {
+    let Some(parent) = select(parents, spec) else {
+        return Ok(None);
+    };
+    let Some(linux) = spec.linux() else {
+        return Ok(None);
+    };
+    let Some(cgroups_path) = linux.cgroups_path() else {
+        return Ok(None);
+    };
+    let original = cgroups_path.to_string_lossy().into_owned();
+
+    let updated = match original.split(':').collect::<Vec<&str>>()[..] {
+        [_slice, prefix, name] => {
+            if !is_slice(parent) {
+                bail!(
+                    "Container uses a systemd cgroup, but the cgroup parent {parent} is not a slice"
+                );
+            }
+            format!("{parent}:{prefix}:{name}")
+        }
+        _ => {
+            if is_slice(parent) {
+                bail!(
+                    "Container uses a cgroupfs cgroup, but the cgroup parent {parent} is a slice"
+                );
+            }
+            let Some(name) = cgroups_path
+                .file_name()
+                .filter(|_| cgroups_path.components().all(|c| c != Component::ParentDir))
+            else {
+                bail!("Unable to determine the cgroup name from cgroups path {original}");
+            };
+            PathBuf::from(parent)
+                .join(name)
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+
+    let mut linux = linux.clone();
+    linux.set_cgroups_path(Some(PathBuf::from(&updated)));
+    spec.set_linux(Some(linux));
+    Ok(Some(format!("cgroups path: {original} -> {updated}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(hostname: &str, cgroups_path: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from(hostname)));
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_cgroups_path(Some(PathBuf::from(cgroups_path)));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    fn cgroups_path(spec: &Spec) -> PathBuf {
+        spec.linux()
+            .as_ref()
+            .unwrap()
+            .cgroups_path()
+            .clone()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_cgroup_parents() {
+        assert!(parse_cgroup_parent("challenges.slice").is_ok());
+        assert!(parse_cgroup_parent("/challenges@hostname=chal-*").is_ok());
+        assert!(parse_cgroup_parent("challenges").is_err());
+        assert!(parse_cgroup_parent(".slice").is_err());
+        assert!(parse_cgroup_parent("/challenges/../docker").is_err());
+    }
+
+    #[test]
+    fn rewrites_systemd_cgroups_paths() {
+        let parents = vec![parse_cgroup_parent("challenges.slice").unwrap()];
+        let mut spec = container("chal-1", "system.slice:docker:abc123");
+        let change = modify_cgroups_path(&mut spec, &parents).unwrap();
+        assert_eq!(
+            cgroups_path(&spec),
+            PathBuf::from("challenges.slice:docker:abc123")
+        );
+        assert_eq!(
+            change.as_deref(),
+            Some("cgroups path: system.slice:docker:abc123 -> challenges.slice:docker:abc123")
+        );
+
+        let parents = vec![parse_cgroup_parent("/challenges").unwrap()];
+        assert!(modify_cgroups_path(&mut spec, &parents).is_err());
+    }
+
+    #[test]
+    fn rewrites_cgroupfs_paths_by_selector() {
+        let parents = vec![
+            parse_cgroup_parent("/challenges@hostname=chal-*").unwrap(),
+            parse_cgroup_parent("/judges@hostname=judge-*").unwrap(),
+        ];
+        let mut spec = container("chal-1", "/docker/abc123");
+        modify_cgroups_path(&mut spec, &parents).unwrap();
+        assert_eq!(cgroups_path(&spec), PathBuf::from("/challenges/abc123"));
+
+        let mut spec = container("web", "/docker/abc123");
+        assert_eq!(modify_cgroups_path(&mut spec, &parents).unwrap(), None);
+        assert_eq!(cgroups_path(&spec), PathBuf::from("/docker/abc123"));
+
+        let parents = vec![parse_cgroup_parent("challenges.slice").unwrap()];
+        let mut spec = container("chal-1", "/docker/abc123");
+        assert!(modify_cgroups_path(&mut spec, &parents).is_err());
+    }
+}
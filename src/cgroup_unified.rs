@@ -0,0 +1,385 @@
+use anyhow::{Context, bail};
+use oci_spec::runtime::{LinuxResources, Spec};
+use std::path::Path;
+
+/// Returns whether the host uses cgroup v2, given the cgroup filesystem mountpoint (usually
+/// `/sys/fs/cgroup`).
+pub(crate) fn is_cgroup_v2(cgroup_root: &Path) -> bool {
+    cgroup_root.join("cgroup.controllers").exists()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct UnifiedEntry {
+    key: String,
+    value: String,
+}
+
+pub(crate) fn parse_unified_entry(value: &str) -> Result<UnifiedEntry, anyhow::Error> {
+    let err_msg = "unified cgroup entries must be in KEY=VALUE format";
+    let (key, value) = value.split_once('=').context(err_msg)?;
+    if key.is_empty() || value.is_empty() {
+        return Err(anyhow::anyhow!(err_msg));
+    }
+    match key.split_once('.') {
+        Some((controller, name)) if !controller.is_empty() && !name.is_empty() => {}
+        _ => bail!("unified cgroup keys must be in CONTROLLER.NAME format (e.g. memory.high)"),
+    }
+    if key.contains('/') {
+        bail!("unified cgroup keys must not contain '/'");
+    }
+    Ok(UnifiedEntry {
+        key: String::from(key),
+        value: String::from(value),
+    })
+}
+
+/// Unified cgroup configuration for containers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UnifiedPolicy {
+    /// Entries to write to `linux.resources.unified`, overriding any original values.
+    pub(crate) entries: Vec<UnifiedEntry>,
+    /// Translate structured resource limits to their unified equivalents.
+    pub(crate) translate: bool,
+}
+
+impl UnifiedPolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty() && !self.translate
+    }
+}
+
+/// Returns the unified keys which are set through structured fields, along with their
+/// equivalent unified values (if they can be translated).
+///
+/// Conversions match those performed by runc on cgroup v2 hosts.
+fn structured_values(resources: &LinuxResources) -> Vec<(&'static str, Option<String>)> {
+    let mut values = Vec::new();
+    let max = |v: i64| {
+        if v < 0 {
+            String::from("max")
+        } else {
+            v.to_string()
+        }
+    };
+    if let Some(memory) = resources.memory() {
+        if let Some(limit) = memory.limit() {
+            values.push(("memory.max", Some(max(limit))));
+        }
+        if let Some(swap) = memory.swap() {
+            // cgroup v2 limits swap alone, rather than memory+swap
+            let value = match memory.limit() {
+                _ if swap < 0 => Some(String::from("max")),
+                Some(limit) if limit >= 0 && swap >= limit => Some((swap - limit).to_string()),
+                _ => None,
+            };
+            values.push(("memory.swap.max", value));
+        }
+        if let Some(reservation) = memory.reservation() {
+            values.push(("memory.low", Some(max(reservation))));
+        }
+    }
+    if let Some(cpu) = resources.cpu() {
+        if cpu.quota().is_some() || cpu.period().is_some() {
+            let quota = cpu.quota().map_or_else(|| String::from("max"), max);
+            let period = cpu.period().unwrap_or(100_000);
+            values.push(("cpu.max", Some(format!("{quota} {period}"))));
+        }
+        if let Some(shares) = cpu.shares().filter(|s| *s > 0) {
+            let weight = 1 + (shares.clamp(2, 262_144) - 2) * 9999 / 262_142;
+            values.push(("cpu.weight", Some(weight.to_string())));
+        }
+        if let Some(idle) = cpu.idle() {
+            values.push(("cpu.idle", Some(idle.to_string())));
+        }
+        if let Some(cpus) = cpu.cpus() {
+            values.push(("cpuset.cpus", Some(cpus.clone())));
+        }
+        if let Some(mems) = cpu.mems() {
+            values.push(("cpuset.mems", Some(mems.clone())));
+        }
+    }
+    if let Some(pids) = resources.pids() {
+        let limit = pids.limit();
+        values.push(("pids.max", Some(max(if limit > 0 { limit } else { -1 }))));
+    }
+    if let Some(weight) = resources.block_io().as_ref().and_then(|b| b.weight()) {
+        let weight = 1 + (u32::from(weight.clamp(10, 1000)) - 10) * 9999 / 990;
+        values.push(("io.weight", Some(weight.to_string())));
+    }
+    values
+}
+
+/// Returns the unified keys which conflict with the structured fields set in `resources`.
+pub(crate) fn conflicting_unified_keys(resources: &LinuxResources) -> Vec<&'static str> {
+    structured_values(resources)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Clears the structured field equivalent to a unified key.
+fn clear_structured(resources: &mut LinuxResources, key: &str) {
+    if let Some(mut memory) = resources.memory().to_owned() {
+        match key {
+            "memory.max" => memory.set_limit(None),
+            "memory.swap.max" => memory.set_swap(None),
+            "memory.low" => memory.set_reservation(None),
+            _ => &mut memory,
+        };
+        resources.set_memory(Some(memory));
+    }
+    if let Some(mut cpu) = resources.cpu().clone() {
+        match key {
+            "cpu.max" => cpu.set_quota(None).set_period(None),
+            "cpu.weight" => cpu.set_shares(None),
+            "cpu.idle" => cpu.set_idle(None),
+            "cpuset.cpus" => cpu.set_cpus(None),
+            "cpuset.mems" => cpu.set_mems(None),
+            _ => &mut cpu,
+        };
+        resources.set_cpu(Some(cpu));
+    }
+    if key == "pids.max" {
+        resources.set_pids(None);
+    }
+    if key == "io.weight"
+        && let Some(mut block_io) = resources.block_io().clone()
+    {
+        block_io.set_weight(None);
+        resources.set_block_io(Some(block_io));
+    }
+}
+
+/// Writes unified (cgroup v2) resource entries, resolving conflicts with the structured resource
+/// fields.
+///
+/// Unified entries supplied by the container which conflict with a structured field are removed,
+/// so that limits enforced through the structured fields cannot be bypassed. If enabled,
+/// structured fields are then translated to their unified equivalents. Finally, entries from the
+/// policy are written, replacing any conflicting structured field.
+///
+/// Nothing is changed on cgroup v1 hosts, as runtimes reject unified entries there.
+///
+/// Returns a description of each change which was made.
+pub(crate) fn modify_unified_resources(
+    spec: &mut Spec,
+    policy: &UnifiedPolicy,
+    cgroup_v2: bool,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    if !cgroup_v2 {
+        if !policy.entries.is_empty() {
+            changes.push(String::from(
+                "unified: skipped unified entries on cgroup v1 host",
+            ));
+        }
+        return changes;
+    }
+    let Some(linux) = spec.linux() else {
+        return changes;
+    };
+    let mut linux = linux.clone();
+    let mut resources = linux.resources().clone().unwrap_or_default();
+    let mut unified = resources.unified().clone().unwrap_or_default();
+
+    for key in conflicting_unified_keys(&resources) {
+        if let Some(value) = unified.remove(key) {
+            changes.push(format!(
+                "unified: removed {key}={value}, which conflicts with a structured limit"
+            ));
+        }
+    }
+
+    if policy.translate {
+        for (key, value) in structured_values(&resources) {
+            match value {
+                Some(value) => {
+                    changes.push(format!(
+                        "unified: translated structured limit to {key}={value}"
+                    ));
+                    unified.insert(String::from(key), value);
+                    clear_structured(&mut resources, key);
+                }
+                None => changes.push(format!(
+                    "unified: unable to translate structured limit to {key}"
+                )),
+            }
+        }
+    }
+
+    for entry in &policy.entries {
+        if structured_values(&resources)
+            .iter()
+            .any(|(key, _)| *key == entry.key)
+        {
+            clear_structured(&mut resources, &entry.key);
+            changes.push(format!(
+                "unified: {} replaces the equivalent structured limit",
+                entry.key
+            ));
+        }
+        unified.insert(entry.key.clone(), entry.value.clone());
+        changes.push(format!("unified: set {}={}", entry.key, entry.value));
+    }
+
+    resources.set_unified(if unified.is_empty() {
+        None
+    } else {
+        Some(unified)
+    });
+    linux.set_resources(Some(resources));
+    spec.set_linux(Some(linux));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{LinuxCpu, LinuxMemory, LinuxPids};
+    use std::{collections::HashMap, fs};
+
+    fn spec_with_resources(resources: LinuxResources) -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    fn resources(spec: &Spec) -> LinuxResources {
+        spec.linux().as_ref().unwrap().resources().clone().unwrap()
+    }
+
+    fn unified(spec: &Spec) -> HashMap<String, String> {
+        resources(spec).unified().clone().unwrap_or_default()
+    }
+
+    fn limited_resources() -> LinuxResources {
+        let mut memory = LinuxMemory::default();
+        memory.set_limit(Some(1 << 30));
+        memory.set_swap(Some(3 << 29));
+        let mut cpu = LinuxCpu::default();
+        cpu.set_quota(Some(50_000));
+        cpu.set_shares(Some(1024));
+        let mut pids = LinuxPids::default();
+        pids.set_limit(256);
+        let mut resources = LinuxResources::default();
+        resources.set_memory(Some(memory));
+        resources.set_cpu(Some(cpu));
+        resources.set_pids(Some(pids));
+        resources
+    }
+
+    #[test]
+    fn detects_cgroup_v2() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!is_cgroup_v2(root.path()));
+        fs::write(
+            root.path().join("cgroup.controllers"),
+            "cpu io memory pids\n",
+        )
+        .unwrap();
+        assert!(is_cgroup_v2(root.path()));
+    }
+
+    #[test]
+    fn parses_unified_entries() {
+        assert_eq!(
+            parse_unified_entry("memory.high=512M").unwrap(),
+            UnifiedEntry {
+                key: String::from("memory.high"),
+                value: String::from("512M"),
+            }
+        );
+        assert_eq!(
+            parse_unified_entry("io.max=8:0 rbps=1048576")
+                .unwrap()
+                .value,
+            "8:0 rbps=1048576"
+        );
+        assert!(parse_unified_entry("memory=1").is_err());
+        assert!(parse_unified_entry(".high=1").is_err());
+        assert!(parse_unified_entry("memory.high=").is_err());
+        assert!(parse_unified_entry("../memory.high=1").is_err());
+    }
+
+    #[test]
+    fn writes_entries_on_v2() {
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let policy = UnifiedPolicy {
+            entries: vec![
+                parse_unified_entry("memory.high=512M").unwrap(),
+                parse_unified_entry("memory.oom.group=1").unwrap(),
+            ],
+            translate: false,
+        };
+        modify_unified_resources(&mut spec, &policy, true);
+        let unified = unified(&spec);
+        assert_eq!(unified["memory.high"], "512M");
+        assert_eq!(unified["memory.oom.group"], "1");
+    }
+
+    #[test]
+    fn skips_entries_on_v1() {
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let policy = UnifiedPolicy {
+            entries: vec![parse_unified_entry("memory.high=512M").unwrap()],
+            translate: true,
+        };
+        let changes = modify_unified_resources(&mut spec, &policy, false);
+        assert!(unified(&spec).is_empty());
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn policy_entries_replace_structured_fields() {
+        let mut spec = spec_with_resources(limited_resources());
+        let policy = UnifiedPolicy {
+            entries: vec![parse_unified_entry("memory.max=2G").unwrap()],
+            translate: false,
+        };
+        modify_unified_resources(&mut spec, &policy, true);
+        assert_eq!(resources(&spec).memory().unwrap().limit(), None);
+        assert_eq!(unified(&spec)["memory.max"], "2G");
+        // Unrelated structured fields are kept
+        assert_eq!(resources(&spec).pids().unwrap().limit(), 256);
+    }
+
+    #[test]
+    fn removes_conflicting_container_entries() {
+        let mut resources = limited_resources();
+        resources.set_unified(Some(HashMap::from([
+            (String::from("memory.max"), String::from("max")),
+            (String::from("memory.high"), String::from("1G")),
+        ])));
+        let mut spec = spec_with_resources(resources);
+        modify_unified_resources(&mut spec, &UnifiedPolicy::default(), true);
+        let unified = unified(&spec);
+        assert!(!unified.contains_key("memory.max"));
+        assert_eq!(unified["memory.high"], "1G");
+        assert_eq!(
+            self::resources(&spec).memory().unwrap().limit(),
+            Some(1 << 30)
+        );
+    }
+
+    #[test]
+    fn translates_structured_fields() {
+        let mut spec = spec_with_resources(limited_resources());
+        let policy = UnifiedPolicy {
+            entries: vec![],
+            translate: true,
+        };
+        modify_unified_resources(&mut spec, &policy, true);
+        let unified = unified(&spec);
+        assert_eq!(unified["memory.max"], (1u64 << 30).to_string());
+        assert_eq!(unified["memory.swap.max"], (1u64 << 29).to_string());
+        assert_eq!(unified["cpu.max"], "50000 100000");
+        assert_eq!(unified["cpu.weight"], "39");
+        assert_eq!(unified["pids.max"], "256");
+        let resources = resources(&spec);
+        assert_eq!(resources.memory().unwrap().limit(), None);
+        assert_eq!(resources.cpu().as_ref().unwrap().quota(), None);
+        assert!(resources.pids().is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{
+    selector::{Selected, parse_selected, select},
+    state_file::with_state,
+};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+/// Parses a Linux CPU or memory node list (e.g. `0-3,8,10-11`).
+pub(crate) fn parse_cpu_list(value: &str) -> Result<BTreeSet<u32>, anyhow::Error> {
+    let err_msg = "CPU lists must be comma-separated numbers or ranges (e.g. 0-3,8)";
+    let mut ids = BTreeSet::new();
+    for item in value.split(',') {
+        let (start, end) = match item.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (item, item),
+        };
+        let start: u32 = start.trim().parse().context(err_msg)?;
+        let end: u32 = end.trim().parse().context(err_msg)?;
+        if start > end {
+            bail!(err_msg);
+        }
+        ids.extend(start..=end);
+    }
+    Ok(ids)
+}
+
+/// Formats a set of CPU or memory node IDs as a Linux CPU list.
+fn format_cpu_list(ids: &BTreeSet<u32>) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &id in ids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Parses a number of CPUs or memory nodes to allocate, in `COUNT` or `COUNT@SELECTOR` format.
+pub(crate) fn parse_cpuset_count(value: &str) -> Result<Selected<usize>, anyhow::Error> {
+    let selected = parse_selected(value)?;
+    let count = selected
+        .value
+        .parse()
+        .context("cpuset counts must be non-negative integers")?;
+    Ok(Selected {
+        value: count,
+        selector: selected.selector,
+    })
+}
+
+/// Exclusive cpuset allocation configuration.
+#[derive(Clone, Debug)]
+pub(crate) struct CpusetPolicy {
+    /// CPUs which may be allocated to containers.
+    pub(crate) cpus: BTreeSet<u32>,
+    /// Memory nodes which may be allocated to containers.
+    pub(crate) mems: BTreeSet<u32>,
+    /// Number of CPUs to allocate to each container.
+    pub(crate) cpu_counts: Vec<Selected<usize>>,
+    /// Number of memory nodes to allocate to each container.
+    pub(crate) mem_counts: Vec<Selected<usize>>,
+    /// File tracking the current allocations.
+    pub(crate) state_path: PathBuf,
+}
+
+impl CpusetPolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.cpu_counts.is_empty() && self.mem_counts.is_empty()
+    }
+}
+
+/// CPUs and memory nodes allocated to a container.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Allocation {
+    cpus: BTreeSet<u32>,
+    mems: BTreeSet<u32>,
+}
+
+/// Allocations, keyed by container ID.
+type Allocations = BTreeMap<String, Allocation>;
+
+/// Runs a function on the allocations stored in a state file, while holding an exclusive lock on
+/// it, and saves any changes.
+fn with_allocations<T>(
+    state_path: &Path,
+    f: impl FnOnce(&mut Allocations) -> Result<T>,
+) -> Result<T> {
+    with_state(state_path, "cpuset", f)
+}
+
+/// Takes `count` IDs from a pool, skipping those already in use.
+fn take(pool: &BTreeSet<u32>, used: &BTreeSet<u32>, count: usize) -> Option<BTreeSet<u32>> {
+    let ids: BTreeSet<u32> = pool.difference(used).take(count).copied().collect();
+    (ids.len() == count).then_some(ids)
+}
+
+/// CPUs and memory nodes allocated to a container by [`allocate_cpuset`].
+pub(crate) struct CpusetAllocation {
+    /// A description of the allocation.
+    pub(crate) change: String,
+    /// Must be released if the container cannot be created. Unset if an existing allocation was
+    /// reused.
+    pub(crate) reservation: Option<CpusetReservation>,
+}
+
+/// Allocates CPUs and memory nodes which are not used by any other container, and confines the
+/// container to them.
+///
+/// An existing allocation of the same size for the container ID is reused, e.g. when a container
+/// which was not deleted before the host restarted is created again. Fails if the container ID has
+/// an allocation of a different size, or if the pool does not have enough free CPUs or memory
+/// nodes.
+pub(crate) fn allocate_cpuset(
+    spec: &mut Spec,
+    container_id: &str,
+    policy: &CpusetPolicy,
+) -> Result<Option<CpusetAllocation>> {
+    let cpu_count = select(&policy.cpu_counts, spec).copied().unwrap_or(0);
+    let mem_count = select(&policy.mem_counts, spec).copied().unwrap_or(0);
+    if cpu_count == 0 && mem_count == 0 {
+        return Ok(None);
+    }
+    let Some(linux) = spec.linux() else {
+        return Ok(None);
+    };
+
+    let (cpu_list, mem_list, reserved) = with_allocations(&policy.state_path, |allocations| {
+        // A live container may already use this ID, so its allocation is never taken over
+        if let Some(existing) = allocations.get(container_id) {
+            if existing.cpus.len() != cpu_count || existing.mems.len() != mem_count {
+                bail!(
+                    "container {container_id} already has a cpuset allocation of {} CPUs and {} \
+                     memory nodes",
+                    existing.cpus.len(),
+                    existing.mems.len()
+                );
+            }
+            return Ok((
+                format_cpu_list(&existing.cpus),
+                format_cpu_list(&existing.mems),
+                false,
+            ));
+        }
+        let used_cpus = allocations
+            .values()
+            .flat_map(|a| &a.cpus)
+            .copied()
+            .collect();
+        let used_mems = allocations
+            .values()
+            .flat_map(|a| &a.mems)
+            .copied()
+            .collect();
+        let Some(cpus) = take(&policy.cpus, &used_cpus, cpu_count) else {
+            bail!("cpuset pool exhausted: unable to allocate {cpu_count} CPUs");
+        };
+        let Some(mems) = take(&policy.mems, &used_mems, mem_count) else {
+            bail!("cpuset pool exhausted: unable to allocate {mem_count} memory nodes");
+        };
+        let lists = (format_cpu_list(&cpus), format_cpu_list(&mems), true);
+        allocations.insert(String::from(container_id), Allocation { cpus, mems });
+        Ok(lists)
+    })?;
+
+    let mut linux = linux.clone();
+    let mut resources = linux.resources().clone().unwrap_or_default();
+    let mut cpu = resources.cpu().clone().unwrap_or_default();
+    let mut change = String::from(if reserved {
+        "cpuset: allocated"
+    } else {
+        "cpuset: reused allocation of"
+    });
+    if cpu_count > 0 {
+        change.push_str(&format!(" cpus {cpu_list}"));
+        cpu.set_cpus(Some(cpu_list));
+    }
+    if mem_count > 0 {
+        change.push_str(&format!(" mems {mem_list}"));
+        cpu.set_mems(Some(mem_list));
+    }
+    resources.set_cpu(Some(cpu));
+    linux.set_resources(Some(resources));
+    spec.set_linux(Some(linux));
+    Ok(Some(CpusetAllocation {
+        change,
+        reservation: reserved.then(|| CpusetReservation::new(container_id, &policy.state_path)),
+    }))
+}
+
+/// Frees the CPUs and memory nodes allocated to a container, if any.
+pub(crate) fn release_cpuset(container_id: &str, state_path: &Path) -> Result<()> {
+    with_allocations(state_path, |allocations| {
+        allocations.remove(container_id);
+        Ok(())
+    })
+}
+
+/// A newly made cpuset allocation, which must be released if the container is never created (in
+/// which case its `poststop` hook would never run), so that its CPUs and memory nodes are not
+/// leaked. Dropping the reservation keeps the allocation.
+pub(crate) struct CpusetReservation {
+    container_id: String,
+    state_path: PathBuf,
+}
+
+impl CpusetReservation {
+    fn new(container_id: &str, state_path: &Path) -> Self {
+        CpusetReservation {
+            container_id: String::from(container_id),
+            state_path: state_path.to_path_buf(),
+        }
+    }
+
+    /// Frees the reserved CPUs and memory nodes.
+    pub(crate) fn release(self) -> Result<()> {
+        release_cpuset(&self.container_id, &self.state_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Returns a policy whose state file is removed along with the returned directory.
+    fn policy(cpu_counts: &[&str]) -> (TempDir, CpusetPolicy) {
+        let state_dir = tempfile::tempdir().unwrap();
+        let policy = CpusetPolicy {
+            cpus: parse_cpu_list("4-7").unwrap(),
+            mems: parse_cpu_list("0").unwrap(),
+            cpu_counts: cpu_counts
+                .iter()
+                .map(|c| parse_cpuset_count(c).unwrap())
+                .collect(),
+            mem_counts: vec![],
+            state_path: state_dir.path().join("cpusets.json"),
+        };
+        (state_dir, policy)
+    }
+
+    fn container(hostname: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from(hostname)));
+        spec
+    }
+
+    fn cpus(spec: &Spec) -> Option<String> {
+        spec.linux()
+            .as_ref()?
+            .resources()
+            .as_ref()?
+            .cpu()
+            .as_ref()?
+            .cpus()
+            .clone()
+    }
+
+    #[test]
+    fn parses_and_formats_cpu_lists() {
+        let ids = parse_cpu_list("0-3,8,10-11").unwrap();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(format_cpu_list(&ids), "0-3,8,10-11");
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("").is_err());
+    }
+
+    #[test]
+    fn allocates_exclusive_cpus() {
+        let (_state_dir, policy) = policy(&["2@hostname=judge-*"]);
+        let mut a = container("judge-1");
+        let mut b = container("judge-2");
+        let mut c = container("judge-3");
+        allocate_cpuset(&mut a, "a", &policy).unwrap();
+        allocate_cpuset(&mut b, "b", &policy).unwrap();
+        assert_eq!(cpus(&a).as_deref(), Some("4-5"));
+        assert_eq!(cpus(&b).as_deref(), Some("6-7"));
+        assert!(allocate_cpuset(&mut c, "c", &policy).is_err());
+
+        // Containers which are not selected are not confined
+        let mut web = container("web");
+        assert!(allocate_cpuset(&mut web, "web", &policy).unwrap().is_none());
+        assert_eq!(cpus(&web), None);
+
+        release_cpuset("a", &policy.state_path).unwrap();
+        let allocation = allocate_cpuset(&mut c, "c", &policy).unwrap().unwrap();
+        assert_eq!(allocation.change, "cpuset: allocated cpus 4-5");
+        assert!(allocation.reservation.is_some());
+    }
+
+    #[test]
+    fn reservations_are_released_explicitly() {
+        let (_state_dir, policy) = policy(&["4"]);
+        let allocation = allocate_cpuset(&mut container("judge-1"), "a", &policy).unwrap();
+        allocation.unwrap().reservation.unwrap().release().unwrap();
+        let allocation = allocate_cpuset(&mut container("judge-2"), "b", &policy).unwrap();
+
+        // Dropping a reservation keeps its allocation
+        drop(allocation);
+        assert!(allocate_cpuset(&mut container("judge-1"), "a", &policy).is_err());
+    }
+
+    #[test]
+    fn reallocating_reuses_previous_allocation() {
+        let (_state_dir, policy) = policy(&["2@hostname=judge-1", "4"]);
+        allocate_cpuset(&mut container("judge-1"), "a", &policy).unwrap();
+        assert!(allocate_cpuset(&mut container("judge-2"), "b", &policy).is_err());
+
+        // A duplicate ID neither takes over nor frees the existing allocation
+        let mut spec = container("judge-1");
+        let allocation = allocate_cpuset(&mut spec, "a", &policy).unwrap().unwrap();
+        assert_eq!(allocation.change, "cpuset: reused allocation of cpus 4-5");
+        assert!(allocation.reservation.is_none());
+        assert_eq!(cpus(&spec).as_deref(), Some("4-5"));
+        assert!(allocate_cpuset(&mut container("judge-2"), "a", &policy).is_err());
+        assert!(allocate_cpuset(&mut container("judge-3"), "c", &policy).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    glob::glob_match,
+    selector::{Selected, select_all},
+};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{
+    LinuxDevice, LinuxDeviceBuilder, LinuxDeviceCgroup, LinuxDeviceCgroupBuilder, LinuxDeviceType,
+    Spec,
+};
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+};
+
+/// Character devices which runtimes always create, and may therefore always be accessed.
+///
+/// A minor number of `None` matches all minor numbers.
+const DEFAULT_CGROUP_DEVICES: &[(i64, Option<i64>)] = &[
+    (1, Some(3)), // /dev/null
+    (1, Some(5)), // /dev/zero
+    (1, Some(7)), // /dev/full
+    (1, Some(8)), // /dev/random
+    (1, Some(9)), // /dev/urandom
+    (5, Some(0)), // /dev/tty
+    (5, Some(1)), // /dev/console
+    (5, Some(2)), // /dev/ptmx
+    (136, None),  // /dev/pts/*
+];
+
+/// Device configuration for containers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DevicePolicy {
+    /// Host devices to add to containers.
+    pub(crate) devices: Vec<Selected<String>>,
+    /// Patterns for the paths of requested devices which are permitted.
+    pub(crate) allow: Vec<String>,
+    /// Remove requested devices and device cgroup rules which are not permitted.
+    pub(crate) restrict: bool,
+    /// Reject containers requesting devices which are not permitted instead of removing them.
+    pub(crate) reject: bool,
+}
+
+impl DevicePolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.devices.is_empty() && !self.restrict
+    }
+}
+
+/// Looks up a device node on the host.
+pub(crate) fn host_device(path: &Path) -> Result<LinuxDevice> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("Unable to find host device {}", path.display()))?;
+    let file_type = metadata.file_type();
+    let typ = if file_type.is_char_device() {
+        LinuxDeviceType::C
+    } else if file_type.is_block_device() {
+        LinuxDeviceType::B
+    } else {
+        bail!("{} is not a device", path.display());
+    };
+    let rdev = metadata.rdev();
+    Ok(LinuxDeviceBuilder::default()
+        .path(path)
+        .typ(typ)
+        .major(i64::from(libc::major(rdev)))
+        .minor(i64::from(libc::minor(rdev)))
+        .file_mode(metadata.mode() & 0o777)
+        .uid(metadata.uid())
+        .gid(metadata.gid())
+        .build()?)
+}
+
+fn deny_all() -> LinuxDeviceCgroup {
+    LinuxDeviceCgroupBuilder::default()
+        .allow(false)
+        .access("rwm")
+        .build()
+        .expect("Device cgroup rule should be valid")
+}
+
+fn is_deny_all(rule: &LinuxDeviceCgroup) -> bool {
+    !rule.allow()
+        && rule.typ().is_none_or(|t| t == LinuxDeviceType::A)
+        && rule.major().is_none()
+        && rule.minor().is_none()
+        && rule
+            .access()
+            .as_ref()
+            .is_none_or(|a| ['r', 'w', 'm'].iter().all(|c| a.contains(*c)))
+}
+
+/// Returns whether a device cgroup rule only permits access to default and permitted devices.
+fn is_permitted_rule(rule: &LinuxDeviceCgroup, devices: &[LinuxDevice]) -> bool {
+    if !rule.allow() || rule.access().as_deref() == Some("m") {
+        return true;
+    }
+    let Some(major) = rule.major() else {
+        return false;
+    };
+    let is_char = rule.typ() == Some(LinuxDeviceType::C);
+    if is_char
+        && DEFAULT_CGROUP_DEVICES
+            .iter()
+            .any(|&(mj, mn)| mj == major && (mn.is_none() || mn == rule.minor()))
+    {
+        return true;
+    }
+    let Some(minor) = rule.minor() else {
+        return false;
+    };
+    devices
+        .iter()
+        .any(|d| Some(d.typ()) == rule.typ() && d.major() == major && d.minor() == minor)
+}
+
+/// Adds host devices to the container, and restricts the devices it may access.
+///
+/// If restricted, requested devices whose paths do not match an allowed pattern are removed, as
+/// are device cgroup rules which grant access to anything other than default, added or allowed
+/// devices (e.g. the allow-all rule of privileged containers), and rules granting access to the
+/// permitted devices are added. Device cgroup rules always start with a deny-all rule.
+///
+/// Returns a description of each change which was made.
+pub(crate) fn modify_devices(spec: &mut Spec, policy: &DevicePolicy) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let added: Vec<LinuxDevice> = select_all(&policy.devices, spec)
+        .map(|path| host_device(Path::new(path)))
+        .collect::<Result<_>>()?;
+    let Some(linux) = spec.linux() else {
+        return Ok(changes);
+    };
+    let mut linux = linux.clone();
+
+    // Device nodes
+    let mut devices = Vec::new();
+    for device in linux.devices().clone().unwrap_or_default() {
+        let path = device.path().to_string_lossy();
+        let permitted = !policy.restrict
+            || added.iter().any(|d| d.path() == device.path())
+            || policy.allow.iter().any(|p| glob_match(p, &path));
+        if permitted {
+            devices.push(device);
+        } else if policy.reject {
+            bail!("Container requested non-permitted device {path}");
+        } else {
+            changes.push(format!("devices: removed {path}"));
+        }
+    }
+    for device in &added {
+        devices.retain(|d| d.path() != device.path());
+        devices.push(device.clone());
+        changes.push(format!(
+            "devices: added {} ({} {}:{})",
+            device.path().display(),
+            device.typ().as_str(),
+            device.major(),
+            device.minor()
+        ));
+    }
+
+    // Device cgroup rules
+    let mut resources = linux.resources().clone().unwrap_or_default();
+    let mut rules = Vec::new();
+    for rule in resources.devices().clone().unwrap_or_default() {
+        if !policy.restrict || is_permitted_rule(&rule, &devices) {
+            rules.push(rule);
+        } else if policy.reject {
+            bail!("Container requested non-permitted device access {rule}");
+        } else {
+            changes.push(format!("devices: removed cgroup rule {rule}"));
+        }
+    }
+    if !rules.first().is_some_and(is_deny_all) {
+        rules.insert(0, deny_all());
+    }
+    // Once restricted, permitted devices may no longer be covered by any rule (e.g. those of
+    // privileged containers, which are only granted by the removed allow-all rule)
+    let granted = if policy.restrict { &devices } else { &added };
+    for device in granted {
+        let rule = LinuxDeviceCgroup::from(device);
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    linux.set_devices(if devices.is_empty() {
+        None
+    } else {
+        Some(devices)
+    });
+    resources.set_devices(Some(rules));
+    linux.set_resources(Some(resources));
+    spec.set_linux(Some(linux));
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::parse_selected;
+    use oci_spec::runtime::LinuxResources;
+
+    fn rule(
+        allow: bool,
+        typ: Option<LinuxDeviceType>,
+        major: Option<i64>,
+        access: &str,
+    ) -> LinuxDeviceCgroup {
+        let mut builder = LinuxDeviceCgroupBuilder::default()
+            .allow(allow)
+            .access(access);
+        if let Some(typ) = typ {
+            builder = builder.typ(typ);
+        }
+        if let Some(major) = major {
+            builder = builder.major(major);
+        }
+        builder.build().unwrap()
+    }
+
+    /// A container as created by `docker run --privileged --device /dev/sda`.
+    fn privileged_container() -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_devices(Some(vec![
+            LinuxDeviceBuilder::default()
+                .path("/dev/sda")
+                .typ(LinuxDeviceType::B)
+                .major(8)
+                .minor(0)
+                .build()
+                .unwrap(),
+        ]));
+        let mut resources = LinuxResources::default();
+        resources.set_devices(Some(vec![
+            rule(false, None, None, "rwm"),
+            rule(true, Some(LinuxDeviceType::C), None, "m"),
+            rule(true, Some(LinuxDeviceType::C), Some(136), "rwm"),
+            rule(true, None, None, "rwm"),
+        ]));
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    fn cgroup_rules(spec: &Spec) -> Vec<LinuxDeviceCgroup> {
+        spec.linux()
+            .as_ref()
+            .unwrap()
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .clone()
+            .unwrap()
+    }
+
+    #[test]
+    fn looks_up_host_devices() {
+        let device = host_device(Path::new("/dev/null")).unwrap();
+        assert_eq!(device.typ(), LinuxDeviceType::C);
+        assert_eq!((device.major(), device.minor()), (1, 3));
+        assert!(host_device(Path::new("/etc/hostname")).is_err());
+    }
+
+    #[test]
+    fn adds_devices() {
+        let policy = DevicePolicy {
+            devices: vec![parse_selected("/dev/null").unwrap()],
+            ..Default::default()
+        };
+        let mut spec = Spec::default();
+        let changes = modify_devices(&mut spec, &policy).unwrap();
+        assert_eq!(changes, ["devices: added /dev/null (c 1:3)"]);
+        let linux = spec.linux().as_ref().unwrap();
+        assert_eq!(linux.devices().as_ref().unwrap().len(), 1);
+        let rules = cgroup_rules(&spec);
+        assert!(is_deny_all(&rules[0]));
+        assert_eq!(rules[1].to_string(), "c 1:3 rwm");
+    }
+
+    #[test]
+    fn restricts_privileged_containers() {
+        let policy = DevicePolicy {
+            restrict: true,
+            ..Default::default()
+        };
+        let mut spec = privileged_container();
+        modify_devices(&mut spec, &policy).unwrap();
+        assert!(spec.linux().as_ref().unwrap().devices().is_none());
+        let rules: Vec<String> = cgroup_rules(&spec).iter().map(|r| r.to_string()).collect();
+        assert_eq!(rules, ["a *:* rwm", "c *:* m", "c 136:* rwm"]);
+
+        let policy = DevicePolicy {
+            allow: vec![String::from("/dev/sd*")],
+            restrict: true,
+            ..Default::default()
+        };
+        let mut spec = privileged_container();
+        modify_devices(&mut spec, &policy).unwrap();
+        assert_eq!(
+            spec.linux()
+                .as_ref()
+                .unwrap()
+                .devices()
+                .as_ref()
+                .unwrap()
+                .len(),
+            1
+        );
+        let rules: Vec<String> = cgroup_rules(&spec).iter().map(|r| r.to_string()).collect();
+        assert_eq!(rules, ["a *:* rwm", "c *:* m", "c 136:* rwm", "b 8:0 rwm"]);
+    }
+
+    #[test]
+    fn rejects_privileged_containers() {
+        let policy = DevicePolicy {
+            allow: vec![String::from("/dev/sd*")],
+            restrict: true,
+            reject: true,
+            ..Default::default()
+        };
+        assert!(modify_devices(&mut privileged_container(), &policy).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::Context;
-use oci_spec::runtime::Spec;
+use oci_spec::runtime::{Process, Spec};
 
 #[derive(Clone, Debug)]
 pub(crate) struct EnvVar {
@@ -43,29 +43,34 @@ impl EnvVarOverride {
 }
 
 /// Overrides environment variables in the container config.
-pub(crate) fn modify_env_vars(spec: &mut Spec, vars: Vec<EnvVarOverride>) {
+pub(crate) fn modify_env_vars(spec: &mut Spec, vars: &[EnvVarOverride]) {
     if let Some(process) = spec.process() {
         let mut new_process = process.clone();
-        if let Some(env) = process.env() {
-            let mut new_env = env.clone();
-            for var in vars {
-                let mut found = false;
-                for existing_var in new_env.iter_mut() {
-                    if existing_var.starts_with(&format!("{}=", var.name)) {
-                        found = true;
-                        if var.force {
-                            *existing_var = format!("{}={}", var.name, var.value);
-                        }
-                        break;
+        modify_process_env_vars(&mut new_process, vars);
+        spec.set_process(Some(new_process));
+    }
+}
+
+/// Overrides environment variables of a process.
+pub(crate) fn modify_process_env_vars(process: &mut Process, vars: &[EnvVarOverride]) {
+    if let Some(env) = process.env() {
+        let mut new_env = env.clone();
+        for var in vars {
+            let mut found = false;
+            for existing_var in new_env.iter_mut() {
+                if existing_var.starts_with(&format!("{}=", var.name)) {
+                    found = true;
+                    if var.force {
+                        *existing_var = format!("{}={}", var.name, var.value);
                     }
+                    break;
                 }
-                if !found {
-                    new_env.push(format!("{}={}", var.name, var.value));
-                }
             }
-            new_process.set_env(Some(new_env));
+            if !found {
+                new_env.push(format!("{}={}", var.name, var.value));
+            }
         }
-        spec.set_process(Some(new_process));
+        process.set_env(Some(new_env));
     }
 }
 
@@ -0,0 +1,299 @@
+use crate::{
+    env_vars::{EnvVarOverride, modify_process_env_vars},
+    runtime_command::subcommand_options,
+    security_labels::{SecurityLabelPolicy, modify_process_labels},
+};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{Capabilities, Capability, LinuxCapabilities, Process, Spec};
+use std::{fs, path::Path, str::FromStr};
+
+/// Loads the process passed to `exec` with `--process`.
+pub(crate) fn load_process(path: &Path) -> Result<Process> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read exec process {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Unable to parse exec process {}", path.display()))
+}
+
+/// Writes an updated `exec` process back out to disk.
+pub(crate) fn save_process(path: &Path, process: &Process) -> Result<()> {
+    fs::write(path, serde_json::to_vec(process)?)
+        .with_context(|| format!("Unable to write exec process {}", path.display()))
+}
+
+/// Returns the capabilities in `requested` which are not in `granted`, e.g. `CAP_SYS_ADMIN`.
+fn excess_capabilities(
+    requested: &Option<Capabilities>,
+    granted: &Option<Capabilities>,
+) -> Vec<String> {
+    requested
+        .iter()
+        .flatten()
+        .filter(|cap| {
+            granted
+                .as_ref()
+                .is_none_or(|granted| !granted.contains(cap))
+        })
+        .map(|cap| format!("CAP_{cap}"))
+        .collect()
+}
+
+/// Rejects capabilities requested for an exec process which are not granted to the container's
+/// process.
+fn check_capabilities(caps: &LinuxCapabilities, container_process: &Process) -> Result<()> {
+    let granted = container_process.capabilities().clone().unwrap_or_default();
+    for (name, requested, granted) in [
+        ("bounding", caps.bounding(), granted.bounding()),
+        ("effective", caps.effective(), granted.effective()),
+        ("inheritable", caps.inheritable(), granted.inheritable()),
+        ("permitted", caps.permitted(), granted.permitted()),
+        ("ambient", caps.ambient(), granted.ambient()),
+    ] {
+        let mut excess = excess_capabilities(requested, granted);
+        if !excess.is_empty() {
+            excess.sort();
+            bail!(
+                "exec process requests {name} capabilities not granted to the container: {}",
+                excess.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Applies the policies enforced at container creation to a process executed in the container.
+///
+/// Environment variable overrides and security labels are applied, `noNewPrivileges` is enabled if
+/// it is enabled for the container, and processes requesting capabilities beyond the container's
+/// (or, if configured, no AppArmor or SELinux confinement) are rejected.
+///
+/// Returns a description of each change which was made.
+pub(crate) fn modify_exec_process(
+    process: &mut Process,
+    container: &Spec,
+    env_var_overrides: &[EnvVarOverride],
+    label_policy: &SecurityLabelPolicy,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let container_process = container.process().clone().unwrap_or_default();
+
+    if let Some(caps) = process.capabilities() {
+        check_capabilities(caps, &container_process)?;
+    }
+
+    for change in modify_process_labels(process, container, label_policy, "exec process")? {
+        changes.push(format!("exec: {change}"));
+    }
+
+    if container_process.no_new_privileges() == Some(true)
+        && process.no_new_privileges() != Some(true)
+    {
+        process.set_no_new_privileges(Some(true));
+        changes.push(String::from("exec: enabled noNewPrivileges"));
+    }
+
+    if !env_var_overrides.is_empty() {
+        let env = process.env().clone();
+        modify_process_env_vars(process, env_var_overrides);
+        if process.env() != &env {
+            changes.push(String::from("exec: applied environment variable overrides"));
+        }
+    }
+    Ok(changes)
+}
+
+/// Applies the security label policy to the labels given by `exec`'s `--apparmor` and
+/// `--process-label` options, which are used when the process is not given with `--process`.
+///
+/// Returns a description of each option which was changed.
+pub(crate) fn modify_exec_label_options(
+    options: &mut [String],
+    container: &Spec,
+    label_policy: &SecurityLabelPolicy,
+) -> Result<Vec<String>> {
+    let label_options: Vec<_> = subcommand_options(options)
+        .into_iter()
+        .filter(|o| o.name == "--apparmor" || o.name == "--process-label")
+        .collect();
+    let mut process = Process::default();
+    for option in &label_options {
+        match option.name.as_str() {
+            "--apparmor" => process.set_apparmor_profile(option.value.clone()),
+            _ => process.set_selinux_label(option.value.clone()),
+        };
+    }
+    modify_process_labels(&mut process, container, label_policy, "exec process")?;
+
+    let mut changes = Vec::new();
+    for option in &label_options {
+        let label = match option.name.as_str() {
+            "--apparmor" => process.apparmor_profile(),
+            _ => process.selinux_label(),
+        };
+        if let Some(label) = label
+            && option.value.as_ref() != Some(label)
+        {
+            option.set_value(options, label);
+            changes.push(format!("exec: set {} {label}", option.name));
+        }
+    }
+    Ok(changes)
+}
+
+/// Rejects capabilities given by `exec`'s `-c`/`--cap` options (e.g. `CAP_SYS_ADMIN`) which are
+/// not granted to the container. runc uses these options when the process is not given with
+/// `--process`.
+///
+/// Like runc, each capability is added to the bounding, effective and permitted sets of the
+/// container's process, and to its ambient set if it has inheritable capabilities.
+pub(crate) fn check_exec_caps(caps: &[String], container: &Spec) -> Result<()> {
+    let added = caps
+        .iter()
+        .map(|cap| {
+            Capability::from_str(cap.strip_prefix("CAP_").unwrap_or(cap))
+                .with_context(|| format!("Unknown capability {cap} given to exec"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if added.is_empty() {
+        return Ok(());
+    }
+
+    let container_process = container.process().clone().unwrap_or_default();
+    let mut requested = container_process.capabilities().clone().unwrap_or_default();
+    let add = |set: &Option<Capabilities>| {
+        let mut set = set.clone().unwrap_or_default();
+        set.extend(added.iter().copied());
+        Some(set)
+    };
+    requested.set_bounding(add(requested.bounding()));
+    requested.set_effective(add(requested.effective()));
+    requested.set_permitted(add(requested.permitted()));
+    if requested.inheritable().is_some() {
+        requested.set_ambient(add(requested.ambient()));
+    }
+    check_capabilities(&requested, &container_process)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{env_vars::EnvVar, selector::parse_selected};
+
+    fn process(json: &str) -> Process {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn container() -> Spec {
+        let mut spec = Spec::default();
+        spec.set_process(Some(process(
+            r#"{
+                "cwd": "/",
+                "user": {"uid": 0, "gid": 0},
+                "noNewPrivileges": true,
+                "capabilities": {
+                    "bounding": ["CAP_CHOWN", "CAP_KILL"],
+                    "effective": ["CAP_CHOWN", "CAP_KILL"],
+                    "permitted": ["CAP_CHOWN", "CAP_KILL"]
+                }
+            }"#,
+        )));
+        spec
+    }
+
+    #[test]
+    fn applies_container_policies() {
+        let mut exec = process(
+            r#"{
+                "cwd": "/",
+                "args": ["sh"],
+                "env": ["PATH=/bin", "LANG=C"],
+                "user": {"uid": 1000, "gid": 1000},
+                "capabilities": {"bounding": ["CAP_KILL"], "effective": ["CAP_KILL"]}
+            }"#,
+        );
+        let overrides = [
+            EnvVarOverride::new(&EnvVar::new("LANG", "C.UTF-8"), true),
+            EnvVarOverride::new(&EnvVar::new("TZ", "UTC"), false),
+        ];
+        let changes =
+            modify_exec_process(&mut exec, &container(), &overrides, &Default::default()).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(exec.no_new_privileges(), Some(true));
+        assert_eq!(
+            exec.env().as_deref().unwrap(),
+            ["PATH=/bin", "LANG=C.UTF-8", "TZ=UTC"]
+        );
+    }
+
+    #[test]
+    fn rejects_excess_capabilities() {
+        let mut exec = process(
+            r#"{
+                "cwd": "/",
+                "user": {"uid": 0, "gid": 0},
+                "capabilities": {
+                    "bounding": ["CAP_KILL", "CAP_SYS_ADMIN", "CAP_NET_ADMIN"],
+                    "effective": ["CAP_KILL"]
+                }
+            }"#,
+        );
+        let err =
+            modify_exec_process(&mut exec, &container(), &[], &Default::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exec process requests bounding capabilities not granted to the container: \
+            CAP_NET_ADMIN, CAP_SYS_ADMIN"
+        );
+
+        let mut exec = process(
+            r#"{"cwd": "/", "user": {"uid": 0, "gid": 0}, "capabilities": {"ambient": ["CAP_KILL"]}}"#,
+        );
+        assert!(modify_exec_process(&mut exec, &container(), &[], &Default::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_excess_cap_options() {
+        let caps = |caps: &[&str]| caps.iter().map(|c| String::from(*c)).collect::<Vec<_>>();
+        assert!(check_exec_caps(&caps(&["CAP_KILL", "CHOWN"]), &container()).is_ok());
+        let err = check_exec_caps(&caps(&["CAP_KILL", "CAP_SYS_ADMIN"]), &container()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exec process requests bounding capabilities not granted to the container: \
+            CAP_SYS_ADMIN"
+        );
+        assert!(check_exec_caps(&caps(&["CAP_BOGUS"]), &container()).is_err());
+    }
+
+    #[test]
+    fn applies_security_label_policy() {
+        let policy = SecurityLabelPolicy {
+            apparmor_profiles: vec![parse_selected("strict").unwrap()],
+            reject_unconfined: true,
+            ..Default::default()
+        };
+        let mut exec = process(
+            r#"{"cwd": "/", "user": {"uid": 0, "gid": 0}, "apparmorProfile": "unconfined"}"#,
+        );
+        let err = modify_exec_process(&mut exec, &container(), &[], &policy).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exec process requested to run without an AppArmor profile"
+        );
+        let mut exec = process(
+            r#"{"cwd": "/", "user": {"uid": 0, "gid": 0}, "selinuxLabel": "system_u:system_r:spc_t:s0"}"#,
+        );
+        assert!(modify_exec_process(&mut exec, &container(), &[], &policy).is_err());
+
+        let mut exec = process(r#"{"cwd": "/", "user": {"uid": 0, "gid": 0}}"#);
+        modify_exec_process(&mut exec, &container(), &[], &policy).unwrap();
+        assert_eq!(exec.apparmor_profile().as_deref(), Some("strict"));
+
+        let options = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let mut args = options(&["exec", "--apparmor", "unconfined", "cid", "sh"]);
+        assert!(modify_exec_label_options(&mut args, &container(), &policy).is_err());
+        let mut args = options(&["exec", "--apparmor=docker-default", "cid", "sh"]);
+        let changes = modify_exec_label_options(&mut args, &container(), &policy).unwrap();
+        assert_eq!(changes, ["exec: set --apparmor strict"]);
+        assert_eq!(args, options(&["exec", "--apparmor=strict", "cid", "sh"]));
+    }
+}
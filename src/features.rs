@@ -0,0 +1,183 @@
+use crate::annotations::ANNOTATION_PREFIX;
+use anyhow::{Result, bail};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+
+/// Fields of the runtime's `linux.seccomp` features describing the seccomp profiles it accepts,
+/// which do not apply to containers whose profiles are replaced by the interceptor.
+const SECCOMP_PROFILE_FEATURES: &[&str] = &["actions", "operators", "knownFlags", "supportedFlags"];
+
+/// Interceptor options which locate files, configure the interceptor itself or only qualify other
+/// options, and so do not by themselves change containers' configs.
+const NON_POLICY_OPTIONS: &[&str] = &[
+    "--oi-runtime-path",
+    "--oi-runtime",
+    "--oi-runtime-state",
+    "--oi-netns-dir",
+    "--oi-write-debug-output",
+    "--oi-debug-output-dir",
+    "--oi-cdi-spec-dir",
+    "--oi-hooks-position",
+    "--oi-cgroup-root",
+    "--oi-cpuset-state",
+    "--oi-version",
+    "--oi-help",
+];
+
+/// The interceptor's own section of the runtime `features` output.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct InterceptorFeatures {
+    pub(crate) version: String,
+    pub(crate) policy_hash: String,
+    /// Interceptor annotations which containers are permitted to set, with the patterns their
+    /// values must match.
+    pub(crate) annotations: BTreeMap<String, Vec<String>>,
+    /// Interceptor options in effect which change containers' configs.
+    pub(crate) policies: Vec<String>,
+    /// Whether containers' seccomp profiles are replaced (`--oi-seccomp-profile`).
+    #[serde(skip)]
+    pub(crate) seccomp_replaced: bool,
+}
+
+/// Returns the names of the interceptor options given in its arguments which change containers'
+/// configs, without their values.
+pub(crate) fn active_policies(args: &[String]) -> Vec<String> {
+    let mut policies: Vec<String> = Vec::new();
+    for arg in args {
+        let Some(name) = arg
+            .split('=')
+            .next()
+            .filter(|name| name.starts_with("--oi-") && !NON_POLICY_OPTIONS.contains(name))
+        else {
+            continue;
+        };
+        if !policies.iter().any(|p| p == name) {
+            policies.push(String::from(name));
+        }
+    }
+    policies
+}
+
+/// Merges the interceptor's section into the runtime's `features` output, and removes the seccomp
+/// entries which do not apply to containers whose profiles are replaced.
+///
+/// Other policies (e.g. for devices, sysctls and hooks) do not correspond to any entries in the
+/// `features` schema, and are only listed in the interceptor's section.
+pub(crate) fn modify_features(
+    features: &mut Value,
+    interceptor: &InterceptorFeatures,
+) -> Result<()> {
+    let Some(features) = features.as_object_mut() else {
+        bail!("Runtime features must be a JSON object");
+    };
+
+    if interceptor.seccomp_replaced
+        && let Some(seccomp) = features
+            .get_mut("linux")
+            .and_then(|linux| linux.get_mut("seccomp"))
+            .and_then(Value::as_object_mut)
+    {
+        for key in SECCOMP_PROFILE_FEATURES {
+            seccomp.remove(*key);
+        }
+    }
+
+    // Only the interceptor itself may advertise interceptor annotations
+    let annotations = features.entry("annotations").or_insert_with(|| json!({}));
+    if let Some(annotations) = annotations.as_object_mut() {
+        annotations.retain(|key, _| !key.starts_with(ANNOTATION_PREFIX));
+        annotations.insert(
+            format!("{ANNOTATION_PREFIX}version"),
+            json!(interceptor.version),
+        );
+    }
+
+    features.insert(
+        String::from(ANNOTATION_PREFIX.trim_end_matches('.')),
+        serde_json::to_value(interceptor)?,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::{parse_annotation_permit, permitted_annotations};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn lists_active_policies() {
+        assert_eq!(
+            active_policies(&args(&[
+                "--oi-runtime-path",
+                "/usr/bin/crun",
+                "--oi-env",
+                "A=1",
+                "--oi-env=B=2",
+                "--oi-sysctl-deny",
+                "kernel.*",
+                "--oi-readonly-networking-mounts",
+                "--oi-cpuset-state=/tmp/cpusets.json",
+                "--oi-write-debug-output",
+            ])),
+            [
+                "--oi-env",
+                "--oi-sysctl-deny",
+                "--oi-readonly-networking-mounts"
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_interceptor_features() {
+        let mut features = json!({
+            "ociVersionMin": "1.0.0",
+            "linux": {
+                "seccomp": {"enabled": true, "actions": ["SCMP_ACT_ALLOW"], "archs": ["SCMP_ARCH_X86_64"]}
+            },
+            "annotations": {
+                "org.opencontainers.runc.version": "1.1.12",
+                "org.picoctf.oci-interceptor.version": "0.0.1"
+            }
+        });
+        let permits = ["profile=strict", "profile=relaxed", "skip"]
+            .map(|p| parse_annotation_permit(p).unwrap());
+        let interceptor = InterceptorFeatures {
+            version: String::from("0.2.2"),
+            policy_hash: String::from("0123456789abcdef"),
+            annotations: permitted_annotations(&permits),
+            policies: args(&["--oi-seccomp-profile"]),
+            seccomp_replaced: true,
+        };
+        modify_features(&mut features, &interceptor).unwrap();
+        assert_eq!(
+            features,
+            json!({
+                "ociVersionMin": "1.0.0",
+                "linux": {
+                    "seccomp": {"enabled": true, "archs": ["SCMP_ARCH_X86_64"]}
+                },
+                "annotations": {
+                    "org.opencontainers.runc.version": "1.1.12",
+                    "org.picoctf.oci-interceptor.version": "0.2.2"
+                },
+                "org.picoctf.oci-interceptor": {
+                    "version": "0.2.2",
+                    "policyHash": "0123456789abcdef",
+                    "annotations": {
+                        "org.picoctf.oci-interceptor.profile": ["strict", "relaxed"],
+                        "org.picoctf.oci-interceptor.skip": ["*"]
+                    },
+                    "policies": ["--oi-seccomp-profile"]
+                }
+            })
+        );
+
+        assert!(modify_features(&mut json!([]), &interceptor).is_err());
+    }
+}
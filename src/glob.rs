@@ -0,0 +1,54 @@
+/// Matches `text` against a shell-style `pattern`, in which `*` matches any sequence of
+/// characters (including none). All other characters match literally.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard in pattern
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_pattern_matches_exactly() {
+        assert!(glob_match("kernel.sem", "kernel.sem"));
+        assert!(!glob_match("kernel.sem", "kernel.semx"));
+        assert!(!glob_match("kernel.sem", "kernel.se"));
+    }
+
+    #[test]
+    fn trailing_wildcard_matches_prefix() {
+        assert!(glob_match("kernel.*", "kernel.shmmax"));
+        assert!(glob_match("kernel.*", "kernel."));
+        assert!(!glob_match("kernel.*", "net.ipv4.ip_forward"));
+    }
+
+    #[test]
+    fn inner_wildcards_match() {
+        assert!(glob_match("chal-*-web", "chal-42-web"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b*", "xxbxxaxx"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn lone_wildcard_matches_everything() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+    }
+}
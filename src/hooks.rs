@@ -0,0 +1,224 @@
+use crate::selector::{Selected, select_all};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{Hook, Hooks, Spec};
+use std::{fs, path::Path};
+
+/// A point in the container lifecycle at which hooks are run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HookStage {
+    Prestart,
+    CreateRuntime,
+    CreateContainer,
+    StartContainer,
+    Poststart,
+    Poststop,
+}
+
+impl HookStage {
+    pub(crate) const ALL: [HookStage; 6] = [
+        HookStage::Prestart,
+        HookStage::CreateRuntime,
+        HookStage::CreateContainer,
+        HookStage::StartContainer,
+        HookStage::Poststart,
+        HookStage::Poststop,
+    ];
+
+    /// Returns the name of the stage in the runtime spec (e.g. `createRuntime`).
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            HookStage::Prestart => "prestart",
+            HookStage::CreateRuntime => "createRuntime",
+            HookStage::CreateContainer => "createContainer",
+            HookStage::StartContainer => "startContainer",
+            HookStage::Poststart => "poststart",
+            HookStage::Poststop => "poststop",
+        }
+    }
+
+    /// Parses a stage from its name in the runtime spec.
+    pub(crate) fn parse(name: &str) -> Result<HookStage, anyhow::Error> {
+        match HookStage::ALL
+            .into_iter()
+            .find(|stage| stage.name() == name)
+        {
+            Some(stage) => Ok(stage),
+            None => bail!("unknown hook stage {name}"),
+        }
+    }
+
+    #[allow(deprecated)] // prestart hooks are still supported by runtimes
+    fn hooks_mut(self, hooks: &mut Hooks) -> &mut Option<Vec<Hook>> {
+        match self {
+            HookStage::Prestart => hooks.prestart_mut(),
+            HookStage::CreateRuntime => hooks.create_runtime_mut(),
+            HookStage::CreateContainer => hooks.create_container_mut(),
+            HookStage::StartContainer => hooks.start_container_mut(),
+            HookStage::Poststart => hooks.poststart_mut(),
+            HookStage::Poststop => hooks.poststop_mut(),
+        }
+    }
+}
+
+/// Adds a hook to the end of a lifecycle stage.
+pub(crate) fn add_hook(spec: &mut Spec, stage: HookStage, hook: Hook) {
+    insert_hooks(spec, stage, vec![hook], false);
+}
+
+/// Adds hooks to the start or end of a lifecycle stage, keeping their relative order.
+///
+/// Hooks which are already present in the stage are not added again, so that re-processing a
+/// config does not duplicate them.
+pub(crate) fn insert_hooks(spec: &mut Spec, stage: HookStage, new_hooks: Vec<Hook>, first: bool) {
+    let mut hooks = spec.hooks().clone().unwrap_or_default();
+    let stage_hooks = stage.hooks_mut(&mut hooks).get_or_insert_default();
+    let new_hooks: Vec<Hook> = new_hooks
+        .into_iter()
+        .filter(|hook| !stage_hooks.contains(hook))
+        .collect();
+    if first {
+        stage_hooks.splice(0..0, new_hooks);
+    } else {
+        stage_hooks.extend(new_hooks);
+    }
+    spec.set_hooks(Some(hooks));
+}
+
+/// Loads hooks from a file in the format of the runtime spec's `hooks` section.
+fn load_hooks(path: &Path) -> Result<Hooks> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read hooks file {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Unable to parse hooks file {}", path.display()))
+}
+
+/// Adds the hooks from each hooks file which applies to the container, either before or after
+/// any hooks the container already has.
+///
+/// Returns a description of each hook which was added.
+pub(crate) fn modify_hooks(
+    spec: &mut Spec,
+    hooks_files: &[Selected<String>],
+    first: bool,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let mut stage_hooks: Vec<(HookStage, Vec<Hook>)> = HookStage::ALL
+        .into_iter()
+        .map(|s| (s, Vec::new()))
+        .collect();
+    for path in select_all(hooks_files, spec) {
+        let mut hooks = load_hooks(Path::new(path))?;
+        for (stage, new_hooks) in &mut stage_hooks {
+            new_hooks.extend(stage.hooks_mut(&mut hooks).take().unwrap_or_default());
+        }
+    }
+    for (stage, new_hooks) in stage_hooks {
+        if new_hooks.is_empty() {
+            continue;
+        }
+        for hook in &new_hooks {
+            changes.push(format!(
+                "hooks: added {} hook {}",
+                stage.name(),
+                hook.path().display()
+            ));
+        }
+        insert_hooks(spec, stage, new_hooks, first);
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::parse_selected;
+    use oci_spec::runtime::HookBuilder;
+    use std::path::{Path, PathBuf};
+
+    fn hooks_file(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(format!("{name}.json"));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn container(hostname: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from(hostname)));
+        let hook = HookBuilder::default()
+            .path("/usr/bin/docker-hook")
+            .build()
+            .unwrap();
+        add_hook(&mut spec, HookStage::Poststop, hook);
+        spec
+    }
+
+    fn poststop_paths(spec: &Spec) -> Vec<PathBuf> {
+        spec.hooks()
+            .as_ref()
+            .unwrap()
+            .poststop()
+            .iter()
+            .flatten()
+            .map(|h| h.path().clone())
+            .collect()
+    }
+
+    #[test]
+    fn parses_hook_stages() {
+        assert_eq!(
+            HookStage::parse("createRuntime").unwrap(),
+            HookStage::CreateRuntime
+        );
+        assert!(HookStage::parse("prestop").is_err());
+    }
+
+    #[test]
+    fn adds_hooks_from_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounting = hooks_file(
+            dir.path(),
+            "accounting",
+            r#"{
+                "poststop": [{"path": "/usr/local/bin/cleanup", "args": ["cleanup", "--all"], "timeout": 10}],
+                "createRuntime": [{"path": "/usr/local/bin/account", "env": ["MODE=start"]}]
+            }"#,
+        );
+        let judge = hooks_file(
+            dir.path(),
+            "judge",
+            r#"{"poststop": [{"path": "/usr/local/bin/judge"}]}"#,
+        );
+        let hooks_files = vec![
+            parse_selected(&accounting).unwrap(),
+            parse_selected(&format!("{judge}@hostname=judge-*")).unwrap(),
+        ];
+
+        let mut spec = container("judge-1");
+        let changes = modify_hooks(&mut spec, &hooks_files, false).unwrap();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            poststop_paths(&spec),
+            [
+                "/usr/bin/docker-hook",
+                "/usr/local/bin/cleanup",
+                "/usr/local/bin/judge"
+            ]
+            .map(PathBuf::from)
+        );
+        modify_hooks(&mut spec, &hooks_files, false).unwrap();
+        assert_eq!(poststop_paths(&spec).len(), 3);
+        let hooks = spec.hooks().as_ref().unwrap();
+        let create_runtime = hooks.create_runtime().as_ref().unwrap();
+        assert_eq!(
+            create_runtime[0].env().as_deref(),
+            Some(&[String::from("MODE=start")][..])
+        );
+
+        let mut spec = container("web");
+        modify_hooks(&mut spec, &hooks_files, true).unwrap();
+        assert_eq!(
+            poststop_paths(&spec),
+            ["/usr/local/bin/cleanup", "/usr/bin/docker-hook"].map(PathBuf::from)
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::hooks::{HookStage, insert_hooks};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{Hook, Spec};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A hook definition file, in the format used by Podman's `hooks.d` directories.
+///
+/// See `oci-hooks(5)`.
+#[derive(Debug, Deserialize)]
+struct HookFile {
+    version: String,
+    hook: Hook,
+    when: When,
+    stages: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct When {
+    always: Option<bool>,
+    annotations: Option<HashMap<String, String>>,
+    commands: Option<Vec<String>>,
+    has_bind_mounts: Option<bool>,
+    #[serde(default)]
+    or: bool,
+}
+
+/// A validated hook definition.
+#[derive(Debug)]
+struct HookDefinition {
+    hook: Hook,
+    always: Option<bool>,
+    annotations: Vec<(Regex, Regex)>,
+    commands: Vec<Regex>,
+    has_bind_mounts: Option<bool>,
+    or: bool,
+    stages: Vec<HookStage>,
+}
+
+impl HookDefinition {
+    fn parse(contents: &str) -> Result<HookDefinition> {
+        let file: HookFile = serde_json::from_str(contents)?;
+        if file.version != "1.0.0" {
+            bail!("unsupported version {}", file.version);
+        }
+        if !file.hook.path().is_absolute() {
+            bail!("hook path {} is not absolute", file.hook.path().display());
+        }
+        if file.stages.is_empty() {
+            bail!("no stages specified");
+        }
+        let when = file.when;
+        if when.always.is_none()
+            && when.annotations.as_ref().is_none_or(HashMap::is_empty)
+            && when.commands.as_ref().is_none_or(Vec::is_empty)
+            && when.has_bind_mounts.is_none()
+        {
+            bail!("no when conditions specified");
+        }
+        Ok(HookDefinition {
+            hook: file.hook,
+            always: when.always,
+            annotations: when
+                .annotations
+                .unwrap_or_default()
+                .iter()
+                .map(|(key, value)| Ok((Regex::new(key)?, Regex::new(value)?)))
+                .collect::<Result<_>>()?,
+            commands: when
+                .commands
+                .unwrap_or_default()
+                .iter()
+                .map(|command| Ok(Regex::new(command)?))
+                .collect::<Result<_>>()?,
+            has_bind_mounts: when.has_bind_mounts,
+            or: when.or,
+            stages: file
+                .stages
+                .iter()
+                .map(|stage| HookStage::parse(stage))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Returns whether the hook's conditions match the container: all of them, or any of them if
+    /// `or` is set.
+    ///
+    /// Each annotation pattern pair must match one of the container's annotations, and the
+    /// container's command must match one of the command patterns.
+    fn matches(&self, spec: &Spec) -> bool {
+        let mut conditions = Vec::new();
+        if let Some(always) = self.always {
+            conditions.push(always);
+        }
+        if let Some(required) = self.has_bind_mounts {
+            conditions.push(required && has_bind_mounts(spec));
+        }
+        for (key_pattern, value_pattern) in &self.annotations {
+            conditions.push(
+                spec.annotations()
+                    .iter()
+                    .flatten()
+                    .any(|(k, v)| key_pattern.is_match(k) && value_pattern.is_match(v)),
+            );
+        }
+        if !self.commands.is_empty()
+            && let Some(process) = spec.process()
+        {
+            let command = process.args().as_ref().and_then(|args| args.first());
+            conditions.push(
+                command.is_some_and(|command| self.commands.iter().any(|c| c.is_match(command))),
+            );
+        }
+        if self.or {
+            conditions.contains(&true)
+        } else {
+            !conditions.is_empty() && !conditions.contains(&false)
+        }
+    }
+}
+
+fn has_bind_mounts(spec: &Spec) -> bool {
+    spec.mounts().iter().flatten().any(|mount| {
+        mount.typ().as_deref() == Some("bind")
+            || mount
+                .options()
+                .iter()
+                .flatten()
+                .any(|o| o == "bind" || o == "rbind")
+    })
+}
+
+/// Returns the hook files in a list of directories, sorted by file name.
+///
+/// Files in later directories replace files with the same name in earlier directories. Missing
+/// directories are ignored.
+fn hook_files(dirs: &[PathBuf]) -> BTreeMap<String, PathBuf> {
+    let mut files = BTreeMap::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+            if path.extension().is_some_and(|e| e == "json")
+                && let Some(name) = path.file_name()
+            {
+                files.insert(name.to_string_lossy().into_owned(), path);
+            }
+        }
+    }
+    files
+}
+
+fn load_hook_file(path: &Path) -> Result<HookDefinition> {
+    let contents = fs::read_to_string(path)?;
+    HookDefinition::parse(&contents)
+}
+
+/// Adds the hooks defined in `hooks.d` directories whose conditions match the container, either
+/// before or after any hooks the container already has.
+///
+/// Invalid hook files are skipped.
+///
+/// Returns a description of each hook which was added, and a warning for each hook file which was
+/// skipped.
+pub(crate) fn modify_hooks_dirs(
+    spec: &mut Spec,
+    dirs: &[PathBuf],
+    first: bool,
+) -> (Vec<String>, Vec<String>) {
+    let mut changes = Vec::new();
+    let mut warnings = Vec::new();
+    let mut stage_hooks: Vec<(HookStage, Vec<Hook>)> = HookStage::ALL
+        .into_iter()
+        .map(|s| (s, Vec::new()))
+        .collect();
+    for path in hook_files(dirs).values() {
+        let definition = match load_hook_file(path)
+            .with_context(|| format!("Skipped invalid hook file {}", path.display()))
+        {
+            Ok(definition) => definition,
+            Err(err) => {
+                warnings.push(format!("{err:#}"));
+                continue;
+            }
+        };
+        if !definition.matches(spec) {
+            continue;
+        }
+        for (stage, hooks) in &mut stage_hooks {
+            if definition.stages.contains(stage) {
+                changes.push(format!(
+                    "hooks.d: added {} hook {} from {}",
+                    stage.name(),
+                    definition.hook.path().display(),
+                    path.display()
+                ));
+                hooks.push(definition.hook.clone());
+            }
+        }
+    }
+    for (stage, hooks) in stage_hooks {
+        if !hooks.is_empty() {
+            insert_hooks(spec, stage, hooks, first);
+        }
+    }
+    (changes, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::MountBuilder;
+    use tempfile::TempDir;
+
+    fn hooks_dir(files: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    fn definition(when: &str, stages: &str) -> String {
+        format!(
+            r#"{{"version": "1.0.0", "hook": {{"path": "/usr/bin/hook"}}, "when": {when}, "stages": {stages}}}"#
+        )
+    }
+
+    fn container(annotations: &[(&str, &str)], command: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_annotations(Some(
+            annotations
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect(),
+        ));
+        let mut process = spec.process().clone().unwrap();
+        process.set_args(Some(vec![String::from(command)]));
+        spec.set_process(Some(process));
+        spec
+    }
+
+    #[test]
+    fn matches_when_conditions() {
+        let matches = |when: &str, spec: &Spec| {
+            HookDefinition::parse(&definition(when, r#"["prestart"]"#))
+                .unwrap()
+                .matches(spec)
+        };
+        let spec = container(&[("org.example.gpu", "nvidia")], "/usr/bin/judge");
+        assert!(matches(r#"{"always": true}"#, &spec));
+        assert!(!matches(r#"{"always": false}"#, &spec));
+        assert!(matches(
+            r#"{"annotations": {"^org\\.example\\.gpu$": "nvidia"}}"#,
+            &spec
+        ));
+        assert!(!matches(r#"{"annotations": {"gpu": "^amd$"}}"#, &spec));
+        assert!(matches(r#"{"commands": ["judge$"]}"#, &spec));
+        assert!(!matches(r#"{"hasBindMounts": true}"#, &spec));
+
+        let mut spec = spec;
+        let mount = MountBuilder::default()
+            .destination("/data")
+            .source("/srv/data")
+            .options(vec![String::from("rbind")])
+            .build()
+            .unwrap();
+        spec.set_mounts(Some(vec![mount]));
+        assert!(matches(r#"{"hasBindMounts": true}"#, &spec));
+
+        // All conditions must match, unless "or" is set
+        let gpu_and_bind_mounts =
+            r#"{"annotations": {"^org\\.example\\.gpu$": "nvidia"}, "hasBindMounts": true}"#;
+        assert!(matches(gpu_and_bind_mounts, &spec));
+        let amd_and_bind_mounts = r#"{"annotations": {"gpu": "^amd$"}, "hasBindMounts": true}"#;
+        assert!(!matches(amd_and_bind_mounts, &spec));
+        assert!(matches(
+            r#"{"annotations": {"gpu": "^amd$"}, "hasBindMounts": true, "or": true}"#,
+            &spec
+        ));
+        assert!(!matches(
+            r#"{"annotations": {"gpu": "^amd$"}, "commands": ["^sh$"], "or": true}"#,
+            &spec
+        ));
+        assert!(!matches(
+            r#"{"annotations": {"gpu": "nvidia", "^other$": ".*"}}"#,
+            &spec
+        ));
+        assert!(!matches(
+            r#"{"commands": ["judge$"], "always": false}"#,
+            &spec
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        let parse = |when: &str, stages: &str| HookDefinition::parse(&definition(when, stages));
+        assert!(parse(r#"{}"#, r#"["prestart"]"#).is_err());
+        assert!(parse(r#"{"always": true}"#, r#"[]"#).is_err());
+        assert!(parse(r#"{"always": true}"#, r#"["prestop"]"#).is_err());
+        assert!(parse(r#"{"commands": ["("]}"#, r#"["prestart"]"#).is_err());
+        assert!(HookDefinition::parse(r#"{"version": "2.0.0"}"#).is_err());
+    }
+
+    #[test]
+    fn adds_matching_hooks_and_skips_invalid_files() {
+        let system = hooks_dir(&[
+            (
+                "01-always.json",
+                &definition(r#"{"always": true}"#, r#"["prestart", "poststop"]"#),
+            ),
+            (
+                "02-overridden.json",
+                &definition(r#"{"always": true}"#, r#"["poststop"]"#),
+            ),
+        ]);
+        let local = hooks_dir(&[
+            (
+                "02-overridden.json",
+                &definition(r#"{"always": false}"#, r#"["poststop"]"#),
+            ),
+            ("03-invalid.json", "{"),
+            ("README", "not a hook"),
+        ]);
+        let mut spec = container(&[], "/bin/sh");
+        let (changes, warnings) = modify_hooks_dirs(
+            &mut spec,
+            &[system.path().to_path_buf(), local.path().to_path_buf()],
+            false,
+        );
+        assert_eq!(changes.len(), 2);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("03-invalid.json"));
+        let hooks = spec.hooks().as_ref().unwrap();
+        assert_eq!(hooks.poststop().as_ref().unwrap().len(), 1);
+    }
+}
@@ -1,20 +1,119 @@
+mod annotations;
+mod builtin_hooks;
+mod cdi;
+mod cgroup_path;
+mod cgroup_unified;
+mod cpuset;
+mod devices;
 mod env_vars;
+mod exec_process;
+mod features;
+mod glob;
+mod hooks;
+mod hooks_dir;
+mod network_namespace;
 mod networking_mounts;
+mod passthrough;
+mod resource_update;
+mod resources;
+mod runtime_command;
+mod runtime_log;
+mod runtime_routing;
+mod seccomp;
+mod seccomp_audit;
+mod security_labels;
+mod selector;
+mod state_file;
+mod sysctls;
+mod template;
 
+use annotations::{
+    AnnotationPermit, Marker, parse_annotation_permit, permitted_annotations, policy_hash,
+    record_config_path, record_marker, sanitize_annotations, skip_requested, take_marker,
+};
 use anyhow::{Context, Result};
+use builtin_hooks::{HOOK_SUBCOMMAND, HookAction, add_builtin_hook, read_state, run_hook_action};
+use cdi::modify_cdi_devices;
+use cgroup_path::{modify_cgroups_path, parse_cgroup_parent};
+use cgroup_unified::{
+    UnifiedEntry, UnifiedPolicy, is_cgroup_v2, modify_unified_resources, parse_unified_entry,
+};
 use clap::{
     Arg, ArgAction, ValueHint, crate_authors, crate_description, crate_name, crate_version,
+    value_parser,
 };
+use cpuset::{
+    CpusetPolicy, CpusetReservation, allocate_cpuset, parse_cpu_list, parse_cpuset_count,
+    release_cpuset,
+};
+use devices::{DevicePolicy, modify_devices};
 use env_vars::{EnvVar, EnvVarOverride, modify_env_vars, parse_env_var};
+use exec_process::{
+    check_exec_caps, load_process, modify_exec_label_options, modify_exec_process, save_process,
+};
+use features::{InterceptorFeatures, active_policies, modify_features};
+use hooks::{HookStage, modify_hooks};
+use hooks_dir::modify_hooks_dirs;
+use network_namespace::modify_network_namespace;
 use networking_mounts::modify_networking_mounts;
 use oci_spec::runtime::Spec;
-use std::{fs, io::Write, path::PathBuf, process};
+use passthrough::{call_oci_runtime, exec_oci_runtime, query_oci_runtime};
+use resource_update::modify_resource_update;
+use resources::{ResourceLimit, ResourcePolicy, modify_resources, parse_resource_limit};
+use runtime_command::{RuntimeCommand, load_container_spec, parse_runtime_command};
+use runtime_log::RuntimeLog;
+use runtime_routing::{forget_runtime, record_runtime, recorded_runtime};
+use seccomp::{load_seccomp_profile, modify_seccomp, modify_seccomp_log_mode};
+use seccomp_audit::generate_seccomp_profile;
+use security_labels::{SecurityLabelPolicy, modify_security_labels};
+use selector::{Selected, parse_selected, select};
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+};
+use sysctls::{Sysctl, SysctlPolicy, modify_sysctls, parse_sysctl};
 
 fn main() -> Result<()> {
+    let mut runtime_log = None;
+    let mut cpuset_reservation = None;
+    let result = intercept(&mut runtime_log, &mut cpuset_reservation);
+    // CPUs are still reserved if the interceptor failed before the container could be created
+    let release_result = cpuset_reservation.map_or(Ok(()), CpusetReservation::release);
+    // Container engines show errors from the runtime's log file, rather than its stderr
+    if let Some(runtime_log) = runtime_log {
+        for err in [&result, &release_result]
+            .into_iter()
+            .filter_map(|r| r.as_ref().err())
+        {
+            if let Err(log_err) = runtime_log.write_error(err) {
+                eprintln!("oci-interceptor: {log_err:#}");
+            }
+        }
+    }
+    match (result, release_result) {
+        (Err(err), Err(release_err)) => {
+            eprintln!("oci-interceptor: {release_err:#}");
+            Err(err)
+        }
+        (result, release_result) => result.and(release_result),
+    }
+}
+
+/// Applies the configured modifications and forwards the call to the OCI runtime. `runtime_log`
+/// is set to the runtime's log file once the runtime options have been parsed, and
+/// `cpuset_reservation` is set while CPUs are reserved for a container which has not been created
+/// yet.
+fn intercept(
+    runtime_log: &mut Option<RuntimeLog>,
+    cpuset_reservation: &mut Option<CpusetReservation>,
+) -> Result<()> {
     let matches = clap::Command::new(crate_name!())
         .version(crate_version!())
         .disable_version_flag(true)
         .disable_help_flag(true)
+        .disable_help_subcommand(true)
         .author(crate_authors!())
         .about(crate_description!())
         .dont_delimit_trailing_values(true)
@@ -25,12 +124,43 @@ fn main() -> Result<()> {
                 .default_value("runc")
                 .help("Path to OCI runtime."),
         )
+        .arg(
+            Arg::new("runtime-routes")
+                .long("oi-runtime")
+                .action(ArgAction::Append)
+                .value_name("RUNTIME[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Forward calls for matching containers to a different OCI runtime"),
+        )
+        .arg(
+            Arg::new("runtime-state")
+                .long("oi-runtime-state")
+                .value_hint(ValueHint::FilePath)
+                .default_value("/var/lib/oci-interceptor/runtimes.json")
+                .help("Location of the runtime routing state file"),
+        )
         .arg(
             Arg::new("readonly-networking-mounts")
                 .long("oi-readonly-networking-mounts")
                 .action(ArgAction::SetTrue)
                 .help("Mount networking files as readonly"),
         )
+        .arg(
+            Arg::new("netns")
+                .long("oi-netns")
+                .value_name("TEMPLATE")
+                .help(
+                    "Join a pre-created network namespace, named by expanding {hostname} and \
+                    {annotation:<key>} in TEMPLATE",
+                ),
+        )
+        .arg(
+            Arg::new("netns-dir")
+                .long("oi-netns-dir")
+                .value_hint(ValueHint::DirPath)
+                .default_value("/var/run/netns")
+                .help("Location of pre-created network namespaces"),
+        )
         .arg(
             Arg::new("write-debug-output")
                 .long("oi-write-debug-output")
@@ -60,6 +190,259 @@ fn main() -> Result<()> {
                 .value_parser(parse_env_var)
                 .help("Override an environment variable, regardless of any original value"),
         )
+        .arg(
+            Arg::new("sysctls")
+                .long("oi-sysctl")
+                .action(ArgAction::Append)
+                .value_name("NAME=VALUE")
+                .value_parser(parse_sysctl)
+                .help("Set a namespaced sysctl, regardless of any original value"),
+        )
+        .arg(
+            Arg::new("sysctl-allow")
+                .long("oi-sysctl-allow")
+                .action(ArgAction::Append)
+                .value_name("PATTERN")
+                .help("Only permit requested sysctls matching a pattern (e.g. net.*)"),
+        )
+        .arg(
+            Arg::new("sysctl-deny")
+                .long("oi-sysctl-deny")
+                .action(ArgAction::Append)
+                .value_name("PATTERN")
+                .help("Do not permit requested sysctls matching a pattern (e.g. kernel.*)"),
+        )
+        .arg(
+            Arg::new("sysctl-reject")
+                .long("oi-sysctl-reject")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Reject containers requesting non-permitted sysctls instead of removing them",
+                ),
+        )
+        .arg(
+            Arg::new("seccomp-profile")
+                .long("oi-seccomp-profile")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Replace the seccomp profile with a Docker or OCI format profile"),
+        )
+        .arg(
+            Arg::new("seccomp-deny")
+                .long("oi-seccomp-deny")
+                .action(ArgAction::Append)
+                .value_name("SYSCALL")
+                .help("Deny a syscall, regardless of the seccomp profile"),
+        )
+        .arg(
+            Arg::new("seccomp-log")
+                .long("oi-seccomp-log")
+                .action(ArgAction::SetTrue)
+                .help("Log and allow syscalls allowed or not matched by the seccomp profile"),
+        )
+        .arg(
+            Arg::new("apparmor-profile")
+                .long("oi-apparmor-profile")
+                .action(ArgAction::Append)
+                .value_name("PROFILE[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Set the AppArmor profile"),
+        )
+        .arg(
+            Arg::new("selinux-label")
+                .long("oi-selinux-label")
+                .action(ArgAction::Append)
+                .value_name("LABEL[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Set the SELinux process label"),
+        )
+        .arg(
+            Arg::new("selinux-mount-label")
+                .long("oi-selinux-mount-label")
+                .action(ArgAction::Append)
+                .value_name("LABEL[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Set the SELinux mount label"),
+        )
+        .arg(
+            Arg::new("reject-unconfined")
+                .long("oi-reject-unconfined")
+                .action(ArgAction::SetTrue)
+                .help("Reject containers requesting no AppArmor or SELinux confinement"),
+        )
+        .arg(
+            Arg::new("cdi")
+                .long("oi-cdi")
+                .action(ArgAction::SetTrue)
+                .help("Add CDI devices requested by cdi.k8s.io/* annotations"),
+        )
+        .arg(
+            Arg::new("cdi-spec-dirs")
+                .long("oi-cdi-spec-dir")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::DirPath)
+                .default_values(["/etc/cdi", "/var/run/cdi"])
+                .help("Location of CDI spec files, in increasing order of precedence"),
+        )
+        .arg(
+            Arg::new("devices")
+                .long("oi-device")
+                .action(ArgAction::Append)
+                .value_name("PATH[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Add a host device (e.g. /dev/fuse)"),
+        )
+        .arg(
+            Arg::new("device-allow")
+                .long("oi-device-allow")
+                .action(ArgAction::Append)
+                .value_name("PATTERN")
+                .help("Only permit requested devices matching a pattern (e.g. /dev/nvidia*)"),
+        )
+        .arg(
+            Arg::new("device-restrict")
+                .long("oi-device-restrict")
+                .action(ArgAction::SetTrue)
+                .help("Remove requested devices and device access which are not permitted"),
+        )
+        .arg(
+            Arg::new("device-reject")
+                .long("oi-device-reject")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Reject containers requesting non-permitted devices instead of removing them",
+                ),
+        )
+        .arg(
+            Arg::new("hooks")
+                .long("oi-hooks")
+                .action(ArgAction::Append)
+                .value_name("FILE[@SELECTOR]")
+                .value_parser(parse_selected)
+                .help("Add the hooks in a file in the format of the runtime spec's hooks section"),
+        )
+        .arg(
+            Arg::new("hooks-dirs")
+                .long("oi-hooks-dir")
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::DirPath)
+                .help("Add hooks from a Podman-style hooks.d directory"),
+        )
+        .arg(
+            Arg::new("hooks-position")
+                .long("oi-hooks-position")
+                .value_parser(["first", "last"])
+                .default_value("last")
+                .help("Whether to add hooks before or after the container's existing hooks"),
+        )
+        .arg(
+            Arg::new("pid-dir")
+                .long("oi-pid-dir")
+                .value_hint(ValueHint::DirPath)
+                .help("Record the PID of each container in <DIR>/<container ID>.pid"),
+        )
+        .arg(
+            Arg::new("resource-defaults")
+                .long("oi-resource-default")
+                .action(ArgAction::Append)
+                .value_name("NAME=VALUE")
+                .value_parser(parse_resource_limit)
+                .help("Set a default value for a cgroup resource limit"),
+        )
+        .arg(
+            Arg::new("resource-ceilings")
+                .long("oi-resource-max")
+                .action(ArgAction::Append)
+                .value_name("NAME=VALUE")
+                .value_parser(parse_resource_limit)
+                .help("Set a maximum value for a cgroup resource limit"),
+        )
+        .arg(
+            Arg::new("cgroup-unified")
+                .long("oi-cgroup-unified")
+                .action(ArgAction::Append)
+                .value_name("KEY=VALUE")
+                .value_parser(parse_unified_entry)
+                .help("Set a cgroup v2 unified resource entry (e.g. memory.high=512M)"),
+        )
+        .arg(
+            Arg::new("cgroup-translate")
+                .long("oi-cgroup-translate")
+                .action(ArgAction::SetTrue)
+                .help("Translate cgroup resource limits to unified entries on cgroup v2 hosts"),
+        )
+        .arg(
+            Arg::new("cgroup-parent")
+                .long("oi-cgroup-parent")
+                .action(ArgAction::Append)
+                .value_name("PARENT[@SELECTOR]")
+                .value_parser(parse_cgroup_parent)
+                .help("Place the container cgroup under a systemd slice or cgroupfs path"),
+        )
+        .arg(
+            Arg::new("cgroup-root")
+                .long("oi-cgroup-root")
+                .value_hint(ValueHint::DirPath)
+                .default_value("/sys/fs/cgroup")
+                .help("Location of the host cgroup filesystem"),
+        )
+        .arg(
+            Arg::new("cpuset-pool")
+                .long("oi-cpuset-pool")
+                .value_name("CPUS")
+                .value_parser(parse_cpu_list)
+                .help("CPUs which may be exclusively allocated to containers (e.g. 4-15)"),
+        )
+        .arg(
+            Arg::new("cpuset-mems-pool")
+                .long("oi-cpuset-mems-pool")
+                .value_name("MEMS")
+                .value_parser(parse_cpu_list)
+                .help("Memory nodes which may be exclusively allocated to containers (e.g. 0-1)"),
+        )
+        .arg(
+            Arg::new("cpuset-cpus")
+                .long("oi-cpuset-cpus")
+                .action(ArgAction::Append)
+                .value_name("COUNT[@SELECTOR]")
+                .value_parser(parse_cpuset_count)
+                .help("Number of CPUs to exclusively allocate to the container"),
+        )
+        .arg(
+            Arg::new("cpuset-mems")
+                .long("oi-cpuset-mems")
+                .action(ArgAction::Append)
+                .value_name("COUNT[@SELECTOR]")
+                .value_parser(parse_cpuset_count)
+                .help("Number of memory nodes to exclusively allocate to the container"),
+        )
+        .arg(
+            Arg::new("cpuset-state")
+                .long("oi-cpuset-state")
+                .value_hint(ValueHint::FilePath)
+                .default_value("/var/lib/oci-interceptor/cpusets.json")
+                .help("Location of the cpuset allocation state file"),
+        )
+        .arg(
+            Arg::new("annotation-permits")
+                .long("oi-annotation-allow")
+                .action(ArgAction::Append)
+                .value_name("NAME[=PATTERN]")
+                .value_parser(parse_annotation_permit)
+                .help(
+                    "Permit containers to set the org.picoctf.oci-interceptor.<NAME> annotation \
+                    (skip or profile)",
+                ),
+        )
+        .arg(
+            Arg::new("record-annotations")
+                .long("oi-record-annotations")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Record the interceptor version, policy hash and applied modifications in \
+                    org.picoctf.oci-interceptor.* annotations",
+                ),
+        )
         .arg(
             Arg::new("version")
                 .long("oi-version")
@@ -79,13 +462,106 @@ fn main() -> Result<()> {
                 .allow_hyphen_values(true)
                 .help("All additional options will be forwarded to the OCI runtime."),
         )
+        .subcommand(
+            clap::Command::new("oi-seccomp-generate")
+                .about("Generate a seccomp profile from audit records of logged syscalls")
+                .arg(
+                    Arg::new("audit-log")
+                        .long("audit-log")
+                        .value_hint(ValueHint::FilePath)
+                        .default_value("/var/log/audit/audit.log")
+                        .help("Audit log to read seccomp records from"),
+                )
+                .arg(
+                    Arg::new("pid")
+                        .long("pid")
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(u32))
+                        .help("Only consider records for a container process"),
+                )
+                .arg(
+                    Arg::new("help")
+                        .long("help")
+                        .action(ArgAction::Help)
+                        .help("Print help"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new(HOOK_SUBCOMMAND)
+                .about("Perform a built-in action when invoked as an OCI hook")
+                .hide(true)
+                .arg(Arg::new("action").required(true).value_parser([
+                    "record-pid",
+                    "remove-pid",
+                    "release-cpuset",
+                ]))
+                .arg(
+                    Arg::new("pid-dir")
+                        .long("pid-dir")
+                        .value_hint(ValueHint::DirPath)
+                        .required_if_eq_any([("action", "record-pid"), ("action", "remove-pid")]),
+                )
+                .arg(
+                    Arg::new("cpuset-state")
+                        .long("cpuset-state")
+                        .value_hint(ValueHint::FilePath)
+                        .required_if_eq("action", "release-cpuset"),
+                ),
+        )
         .get_matches();
 
-    let runtime_path = matches
+    if let Some((HOOK_SUBCOMMAND, matches)) = matches.subcommand() {
+        let path = |id: &str| PathBuf::from(matches.get_one::<String>(id).expect("No path set"));
+        let action = match matches.get_one::<String>("action").map(String::as_str) {
+            Some("record-pid") => HookAction::RecordPid {
+                pid_dir: path("pid-dir"),
+            },
+            Some("remove-pid") => HookAction::RemovePid {
+                pid_dir: path("pid-dir"),
+            },
+            _ => HookAction::ReleaseCpuset {
+                state_path: path("cpuset-state"),
+            },
+        };
+        let state = read_state(std::io::stdin())?;
+        return run_hook_action(&action, &state);
+    }
+
+    if let Some(("oi-seccomp-generate", matches)) = matches.subcommand() {
+        let audit_log_path = matches
+            .get_one::<String>("audit-log")
+            .expect("No audit log set");
+        let audit_log = fs::File::open(audit_log_path)
+            .with_context(|| format!("Unable to open audit log {audit_log_path}"))?;
+        let pids: Vec<u32> = matches
+            .get_many::<u32>("pid")
+            .unwrap_or_default()
+            .copied()
+            .collect();
+        let seccomp = generate_seccomp_profile(BufReader::new(audit_log), &pids)?;
+        serde_json::to_writer_pretty(std::io::stdout(), &seccomp)?;
+        println!();
+        return Ok(());
+    }
+
+    let mut runtime_path = matches
         .get_one::<String>("runtime-path")
-        .expect("No runtime path set");
+        .expect("No runtime path set")
+        .clone();
+
+    let runtime_routes: Vec<Selected<String>> = matches
+        .get_many::<Selected<String>>("runtime-routes")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
 
-    let runtime_options: Vec<String> = matches
+    let runtime_state_path = PathBuf::from(
+        matches
+            .get_one::<String>("runtime-state")
+            .expect("No runtime state file set"),
+    );
+
+    let mut runtime_options: Vec<String> = matches
         .get_many::<String>("runtime-options")
         .with_context(|| "No OCI runtime options provided")?
         .cloned()
@@ -109,15 +585,193 @@ fn main() -> Result<()> {
         env_vars.chain(env_vars_forced).collect()
     };
 
-    // Intercept "create" commands to the underlying OCI runtime
+    let sysctls: Vec<Sysctl> = matches
+        .get_many::<Sysctl>("sysctls")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let sysctl_policy = SysctlPolicy {
+        allow: matches
+            .get_many::<String>("sysctl-allow")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        deny: matches
+            .get_many::<String>("sysctl-deny")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        reject: matches.get_flag("sysctl-reject"),
+    };
+
+    let seccomp_profile_path = matches.get_one::<String>("seccomp-profile");
+
+    let seccomp_deny: Vec<String> = matches
+        .get_many::<String>("seccomp-deny")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let security_label_policy = SecurityLabelPolicy {
+        apparmor_profiles: matches
+            .get_many::<Selected<String>>("apparmor-profile")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        selinux_labels: matches
+            .get_many::<Selected<String>>("selinux-label")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        selinux_mount_labels: matches
+            .get_many::<Selected<String>>("selinux-mount-label")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        reject_unconfined: matches.get_flag("reject-unconfined"),
+    };
+
+    let device_policy = {
+        let allow: Vec<String> = matches
+            .get_many::<String>("device-allow")
+            .unwrap_or_default()
+            .cloned()
+            .collect();
+        let reject = matches.get_flag("device-reject");
+        DevicePolicy {
+            devices: matches
+                .get_many::<Selected<String>>("devices")
+                .unwrap_or_default()
+                .cloned()
+                .collect(),
+            restrict: matches.get_flag("device-restrict") || !allow.is_empty() || reject,
+            allow,
+            reject,
+        }
+    };
+
+    let hooks_files: Vec<Selected<String>> = matches
+        .get_many::<Selected<String>>("hooks")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let hooks_dirs: Vec<PathBuf> = matches
+        .get_many::<String>("hooks-dirs")
+        .unwrap_or_default()
+        .map(PathBuf::from)
+        .collect();
+
+    let cdi_spec_dirs: Vec<PathBuf> = matches
+        .get_many::<String>("cdi-spec-dirs")
+        .unwrap_or_default()
+        .map(PathBuf::from)
+        .collect();
+
+    let resource_policy = ResourcePolicy {
+        defaults: matches
+            .get_many::<ResourceLimit>("resource-defaults")
+            .unwrap_or_default()
+            .copied()
+            .collect(),
+        ceilings: matches
+            .get_many::<ResourceLimit>("resource-ceilings")
+            .unwrap_or_default()
+            .copied()
+            .collect(),
+    };
+
+    let unified_policy = UnifiedPolicy {
+        entries: matches
+            .get_many::<UnifiedEntry>("cgroup-unified")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        translate: matches.get_flag("cgroup-translate"),
+    };
+
+    let cgroup_parents: Vec<Selected<String>> = matches
+        .get_many::<Selected<String>>("cgroup-parent")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let cpuset_policy = CpusetPolicy {
+        cpus: matches
+            .get_one::<BTreeSet<u32>>("cpuset-pool")
+            .cloned()
+            .unwrap_or_default(),
+        mems: matches
+            .get_one::<BTreeSet<u32>>("cpuset-mems-pool")
+            .cloned()
+            .unwrap_or_default(),
+        cpu_counts: matches
+            .get_many::<Selected<usize>>("cpuset-cpus")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        mem_counts: matches
+            .get_many::<Selected<usize>>("cpuset-mems")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        state_path: PathBuf::from(
+            matches
+                .get_one::<String>("cpuset-state")
+                .expect("No cpuset state file set"),
+        ),
+    };
+
+    let annotation_permits: Vec<AnnotationPermit> = matches
+        .get_many::<AnnotationPermit>("annotation-permits")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    // Identify the active policies by the interceptor's own arguments, and the policy files they
+    // refer to
+    let args: Vec<String> = env::args().skip(1).collect();
+    let interceptor_args = &args[..args.len() - runtime_options.len()];
+    let mut policy_paths: Vec<&Path> = Vec::new();
+    policy_paths.extend(seccomp_profile_path.map(Path::new));
+    policy_paths.extend(hooks_files.iter().map(|hooks| Path::new(&hooks.value)));
+    if matches.get_flag("cdi") {
+        policy_paths.extend(cdi_spec_dirs.iter().map(PathBuf::as_path));
+    }
+    policy_paths.extend(hooks_dirs.iter().map(PathBuf::as_path));
+    let active_policy_hash = || policy_hash(interceptor_args, &policy_paths);
+
+    let runtime_command = parse_runtime_command(&runtime_options);
+    *runtime_log = RuntimeLog::new(&runtime_command);
+    let mut runtime_input: Option<Vec<u8>> = None;
+    let mut route_recorded = false;
+
+    // Forward calls for existing containers to the runtime chosen when they were created
+    if !runtime_routes.is_empty()
+        && !matches!(
+            runtime_command.subcommand.as_deref(),
+            Some("create" | "run" | "restore")
+        )
+        && let Some(container_id) = &runtime_command.container_id
+        && let Some(runtime) = recorded_runtime(&runtime_state_path, container_id)?
+    {
+        runtime_path = runtime;
+    }
+
+    // Intercept commands to the underlying OCI runtime which are given a bundle
     //
-    // As a heuristic, we look for the -b or --bundle flag in the provided options. This is not
-    // defined in the spec, but is used by runc for its "create" and "run" commands and appears to
-    // have been adopted by most(?) other runtimes for compatibility purposes.
-    if let Some(bundle_path) = get_bundle_path(&mut runtime_options.clone()) {
+    // The command line is not defined in the spec, but runc's "create", "run" and "restore"
+    // commands take a -b or --bundle option (defaulting to the working directory), which has been
+    // adopted by other runtimes for compatibility purposes.
+    //
+    // crun's --config option is honored, so that the config actually used is modified.
+    let cwd = env::current_dir().with_context(|| "Unable to determine working directory")?;
+    if let Some(config_path) = runtime_command.config_path(&cwd) {
         // Load initial OCI config
-        let config_path = bundle_path.join("config.json");
-        let mut spec_modified = false;
+        let mut applied_modifiers: Vec<&'static str> = Vec::new();
+        let mut config_recorded = false;
+        let mut modification_log: Vec<String> = Vec::new();
         let mut spec = Spec::load(&config_path)
             .with_context(|| "Unable to parse OCI runtime specification")?;
         if matches.get_flag("write-debug-output") {
@@ -137,31 +791,322 @@ fn main() -> Result<()> {
             serde_json::to_writer_pretty(&parsed_file, &spec)?;
         }
 
-        // Make any enabled modifications
-        if matches.get_flag("readonly-networking-mounts") {
-            modify_networking_mounts(&mut spec);
-            spec_modified = true;
+        // Remove the marker left by any previous invocation, so that it cannot be forged. A config
+        // left unchanged since it was intercepted by the same version and policies (e.g. when
+        // runtimes are chained) keeps its marker, and is not modified again.
+        let record_annotations = matches.get_flag("record-annotations");
+        let policy_hash = record_annotations.then(active_policy_hash);
+        let mut intercepted_marker = None;
+        if let Some(policy_hash) = &policy_hash
+            && let Some(marker) = take_marker(&mut spec)
+            && marker.matches(policy_hash, &spec)?
+        {
+            intercepted_marker = Some(marker);
+        }
+        let mut tracked_spec = serde_json::to_value(&spec)?;
+
+        // Remove interceptor annotations the container may not set, before any selectors are
+        // evaluated
+        let changes = sanitize_annotations(&mut spec, &annotation_permits);
+        track_modifier(
+            &mut applied_modifiers,
+            &mut tracked_spec,
+            "annotations",
+            &spec,
+        )?;
+        modification_log.extend(changes);
+
+        // Choose the runtime for the container, even if it has opted out of modifications
+        if !runtime_routes.is_empty() {
+            if let Some(runtime) = select(&runtime_routes, &spec) {
+                runtime_path = runtime.clone();
+            }
+            let container_id = runtime_command
+                .container_id
+                .as_deref()
+                .with_context(|| "Unable to determine container ID for runtime routing")?;
+            match record_runtime(&runtime_state_path, container_id, &runtime_path)? {
+                Some(runtime) => {
+                    runtime_path = runtime;
+                    modification_log.push(format!(
+                        "runtime: forwarding to {runtime_path}, as recorded for the container ID"
+                    ));
+                }
+                None => {
+                    route_recorded = true;
+                    modification_log.push(format!("runtime: forwarding to {runtime_path}"));
+                }
+            }
+        }
+
+        // Make any enabled modifications, unless the container has opted out
+        if let Some(marker) = &intercepted_marker {
+            record_marker(&mut spec, marker);
+            modification_log.push(String::from(
+                "annotations: skipped modifications of config already intercepted",
+            ));
+        } else if skip_requested(&spec) {
+            modification_log.push(String::from("annotations: skipped modifications"));
+        } else {
+            // Alternate config paths are not given to later calls (e.g. exec), which must find the
+            // config to enforce policies
+            if runtime_command.config.is_some() {
+                record_config_path(&mut spec, &config_path);
+                config_recorded = true;
+                tracked_spec = serde_json::to_value(&spec)?;
+            }
+            if matches.get_flag("readonly-networking-mounts") {
+                modify_networking_mounts(&mut spec);
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "networking-mounts",
+                    &spec,
+                )?;
+            }
+            if !env_var_overrides.is_empty() {
+                modify_env_vars(&mut spec, &env_var_overrides);
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "env", &spec)?;
+            }
+            if let Some(netns_template) = matches.get_one::<String>("netns") {
+                let netns_dir = PathBuf::from(
+                    matches
+                        .get_one::<String>("netns-dir")
+                        .expect("No network namespace dir set"),
+                );
+                modify_network_namespace(&mut spec, netns_template, &netns_dir)?;
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "netns", &spec)?;
+            }
+            if !sysctls.is_empty()
+                || !sysctl_policy.allow.is_empty()
+                || !sysctl_policy.deny.is_empty()
+            {
+                modify_sysctls(&mut spec, &sysctls, &sysctl_policy)?;
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "sysctls", &spec)?;
+            }
+            if seccomp_profile_path.is_some() || !seccomp_deny.is_empty() {
+                let seccomp_profile = seccomp_profile_path
+                    .map(|path| load_seccomp_profile(path.as_ref()))
+                    .transpose()?;
+                modify_seccomp(&mut spec, seccomp_profile.as_ref(), &seccomp_deny)?;
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "seccomp", &spec)?;
+            }
+            if matches.get_flag("seccomp-log") {
+                modify_seccomp_log_mode(&mut spec);
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "seccomp-log",
+                    &spec,
+                )?;
+            }
+            if !security_label_policy.is_empty() {
+                modification_log.extend(modify_security_labels(&mut spec, &security_label_policy)?);
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "security-labels",
+                    &spec,
+                )?;
+            }
+            if matches.get_flag("cdi") {
+                let (changes, warnings) = modify_cdi_devices(&mut spec, &cdi_spec_dirs)?;
+                for warning in &warnings {
+                    eprintln!("oci-interceptor: {warning}");
+                }
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "cdi", &spec)?;
+                modification_log.extend(warnings);
+                modification_log.extend(changes);
+            }
+            let hooks_first = matches
+                .get_one::<String>("hooks-position")
+                .is_some_and(|p| p == "first");
+            if !hooks_files.is_empty() {
+                let changes = modify_hooks(&mut spec, &hooks_files, hooks_first)?;
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "hooks", &spec)?;
+                modification_log.extend(changes);
+            }
+            if !hooks_dirs.is_empty() {
+                let (changes, warnings) = modify_hooks_dirs(&mut spec, &hooks_dirs, hooks_first);
+                for warning in &warnings {
+                    eprintln!("oci-interceptor: {warning}");
+                }
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "hooks-dir",
+                    &spec,
+                )?;
+                modification_log.extend(warnings);
+                modification_log.extend(changes);
+            }
+            if !device_policy.is_empty() {
+                modification_log.extend(modify_devices(&mut spec, &device_policy)?);
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "devices", &spec)?;
+            }
+            if !resource_policy.is_empty() {
+                modification_log.extend(modify_resources(&mut spec, &resource_policy));
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "resources",
+                    &spec,
+                )?;
+            }
+            if let Some(change) = modify_cgroups_path(&mut spec, &cgroup_parents)? {
+                modification_log.push(change);
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "cgroup-parent",
+                    &spec,
+                )?;
+            }
+            if !cpuset_policy.is_empty() {
+                let container_id = runtime_command
+                    .container_id
+                    .as_deref()
+                    .with_context(|| "Unable to determine container ID for cpuset allocation")?;
+                if let Some(allocation) = allocate_cpuset(&mut spec, container_id, &cpuset_policy)?
+                {
+                    *cpuset_reservation = allocation.reservation;
+                    modification_log.push(allocation.change);
+                    track_modifier(&mut applied_modifiers, &mut tracked_spec, "cpuset", &spec)?;
+                }
+            }
+            if let Some(pid_dir) = matches.get_one::<String>("pid-dir") {
+                let pid_dir = PathBuf::from(pid_dir);
+                modification_log.push(add_builtin_hook(
+                    &mut spec,
+                    HookStage::CreateRuntime,
+                    &HookAction::RecordPid {
+                        pid_dir: pid_dir.clone(),
+                    },
+                )?);
+                modification_log.push(add_builtin_hook(
+                    &mut spec,
+                    HookStage::Poststop,
+                    &HookAction::RemovePid { pid_dir },
+                )?);
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "pid-dir", &spec)?;
+            }
+            if !cpuset_policy.is_empty() {
+                modification_log.push(add_builtin_hook(
+                    &mut spec,
+                    HookStage::Poststop,
+                    &HookAction::ReleaseCpuset {
+                        state_path: cpuset_policy.state_path.clone(),
+                    },
+                )?);
+                track_modifier(&mut applied_modifiers, &mut tracked_spec, "cpuset", &spec)?;
+            }
+            // Conflicting unified entries are also checked when only resource limits are enforced,
+            // so that they cannot be used to bypass those limits
+            if !unified_policy.is_empty() || !resource_policy.is_empty() {
+                let cgroup_root = PathBuf::from(
+                    matches
+                        .get_one::<String>("cgroup-root")
+                        .expect("No cgroup root set"),
+                );
+                modification_log.extend(modify_unified_resources(
+                    &mut spec,
+                    &unified_policy,
+                    is_cgroup_v2(&cgroup_root),
+                ));
+                track_modifier(
+                    &mut applied_modifiers,
+                    &mut tracked_spec,
+                    "cgroup-unified",
+                    &spec,
+                )?;
+            }
         }
-        if !env_var_overrides.is_empty() {
-            modify_env_vars(&mut spec, env_var_overrides);
-            spec_modified = true;
+
+        // Record how the config was modified
+        if let Some(policy_hash) = &policy_hash
+            && intercepted_marker.is_none()
+        {
+            let marker = Marker::new(policy_hash, &applied_modifiers, &spec)?;
+            record_marker(&mut spec, &marker);
         }
 
         // Write the updated config back out to disk
-        if spec_modified {
+        if !applied_modifiers.is_empty() || record_annotations || config_recorded {
             if matches.get_flag("write-debug-output") {
-                let output_filename = spec
+                let hostname = spec
                     .hostname()
                     .clone()
-                    .unwrap_or(String::from("unknown_hostname"))
-                    + "_modified.json";
+                    .unwrap_or(String::from("unknown_hostname"));
                 fs::create_dir_all(&debug_output_dir)?;
+                let output_filename = hostname.clone() + "_modified.json";
                 let modified_spec = fs::File::create(debug_output_dir.join(output_filename))?;
                 serde_json::to_writer_pretty(&modified_spec, &spec)?;
+                if !modification_log.is_empty() {
+                    let log_filename = hostname + "_modifications.log";
+                    let mut contents = modification_log.join("\n");
+                    contents.push('\n');
+                    fs::write(debug_output_dir.join(log_filename), contents)?;
+                }
             }
             spec.save(&config_path)
                 .with_context(|| "Unable to write updated OCI runtime specification")?;
         }
+    } else if runtime_command.subcommand.as_deref() == Some("exec")
+        && (runtime_command.process.is_some()
+            || !runtime_command.caps.is_empty()
+            || !security_label_policy.is_empty())
+    {
+        // Apply the container's policies to processes executed in it (e.g. by docker exec)
+        let mut container_spec = load_container_spec(&runtime_path, &runtime_command, &cwd)?;
+        sanitize_annotations(&mut container_spec, &annotation_permits);
+        if !skip_requested(&container_spec) {
+            let mut changes = modify_exec_label_options(
+                &mut runtime_options,
+                &container_spec,
+                &security_label_policy,
+            )?;
+            if let Some(process_path) = runtime_command.process_path(&cwd) {
+                let mut process = load_process(&process_path)?;
+                let process_changes = modify_exec_process(
+                    &mut process,
+                    &container_spec,
+                    &env_var_overrides,
+                    &security_label_policy,
+                )?;
+                if !process_changes.is_empty() {
+                    save_process(&process_path, &process)?;
+                }
+                changes.extend(process_changes);
+            } else {
+                check_exec_caps(&runtime_command.caps, &container_spec)?;
+            }
+            if !changes.is_empty() && matches.get_flag("write-debug-output") {
+                append_debug_log(&debug_output_dir, &runtime_command, "exec", &changes)?;
+            }
+        }
+    } else if runtime_command.subcommand.as_deref() == Some("update")
+        && !resource_policy.ceilings.is_empty()
+    {
+        // Clamp resource changes (e.g. by docker update) to the ceilings enforced at creation
+        let mut container_spec = load_container_spec(&runtime_path, &runtime_command, &cwd)?;
+        sanitize_annotations(&mut container_spec, &annotation_permits);
+        if !skip_requested(&container_spec) {
+            let container_resources = container_spec
+                .linux()
+                .as_ref()
+                .and_then(|l| l.resources().clone())
+                .unwrap_or_default();
+            let (changes, input) = modify_resource_update(
+                &mut runtime_options,
+                &resource_policy,
+                &container_resources,
+                std::io::stdin(),
+            )?;
+            runtime_input = input;
+            if !changes.is_empty() && matches.get_flag("write-debug-output") {
+                append_debug_log(&debug_output_dir, &runtime_command, "update", &changes)?;
+            }
+        }
     }
 
     // Forward call to the underlying runtime
@@ -176,86 +1121,98 @@ fn main() -> Result<()> {
             .write_all(format!("{} {}\n", runtime_path, runtime_options.join(" ")).as_bytes())?;
         runtime_calls.flush()?;
     }
-    std::process::exit(call_oci_runtime(runtime_path, runtime_options)?);
-}
 
-/// Extracts the container bundle path from the trailing runtime options, if present.
-///
-/// clap cannot handle parsing this because we don't know that --bundle will appear first in the
-/// list of options to forward to the runtime, and only trailing varargs can be captured.
-fn get_bundle_path(options: &mut [String]) -> Option<PathBuf> {
-    let mut options = options.iter();
-    if let Some(bundle_opt) = options.find(|s| s.starts_with("-b") || s.starts_with("--bundle")) {
-        return match bundle_opt.split_once('=') {
-            Some((_option, path)) => Some(PathBuf::from(path)),
-            None => options.next().map(PathBuf::from),
+    // Describe the interceptor alongside the runtime's own features
+    if runtime_command.subcommand.as_deref() == Some("features") {
+        let (exit_code, output) = query_oci_runtime(&runtime_path, &runtime_options)?;
+        if exit_code != 0 {
+            std::io::stdout().write_all(&output)?;
+            std::process::exit(exit_code);
+        }
+        let mut features: serde_json::Value =
+            serde_json::from_slice(&output).with_context(|| "Unable to parse runtime features")?;
+        let interceptor_features = InterceptorFeatures {
+            version: String::from(crate_version!()),
+            policy_hash: active_policy_hash(),
+            annotations: permitted_annotations(&annotation_permits),
+            policies: active_policies(interceptor_args),
+            seccomp_replaced: seccomp_profile_path.is_some(),
         };
-    }
-    None
-}
-
-/// Calls the actual OCI runtime, passing along any runtime options.
-fn call_oci_runtime(runtime_path: &str, options: Vec<String>) -> Result<i32> {
-    let mut child = process::Command::new(runtime_path)
-        .args(options.as_slice())
-        .spawn()
-        .with_context(|| "Failed to execute underlying OCI runtime")?;
-    let status = child
-        .wait()
-        .with_context(|| "Failed to wait on OCI runtime process")?;
-    match status.code() {
-        Some(code) => Ok(code),
-        None => Ok(-1), // child process was killed by a signal
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn opts(args: &[&str]) -> Vec<String> {
-        args.iter().map(|s| s.to_string()).collect()
-    }
-
-    #[test]
-    fn finds_bundle_short_flag_space_separated() {
-        let mut o = opts(&["create", "-b", "/tmp/bundle", "cid"]);
-        assert_eq!(get_bundle_path(&mut o), Some(PathBuf::from("/tmp/bundle")));
-    }
-
-    #[test]
-    fn finds_bundle_short_flag_with_equals() {
-        let mut o = opts(&["create", "-b=/tmp/bundle", "cid"]);
-        assert_eq!(get_bundle_path(&mut o), Some(PathBuf::from("/tmp/bundle")));
-    }
-
-    #[test]
-    fn finds_bundle_long_flag_space_separated() {
-        let mut o = opts(&["create", "--bundle", "/tmp/bundle", "cid"]);
-        assert_eq!(get_bundle_path(&mut o), Some(PathBuf::from("/tmp/bundle")));
+        modify_features(&mut features, &interceptor_features)?;
+        println!("{}", serde_json::to_string_pretty(&features)?);
+        return Ok(());
     }
 
-    #[test]
-    fn finds_bundle_long_flag_with_equals() {
-        let mut o = opts(&["create", "--bundle=/tmp/bundle", "cid"]);
-        assert_eq!(get_bundle_path(&mut o), Some(PathBuf::from("/tmp/bundle")));
+    // Replace the interceptor with the runtime, unless there is work to do once it exits
+    let track_removal = (!cpuset_policy.is_empty() || !runtime_routes.is_empty())
+        && runtime_command.container_id.is_some()
+        && matches!(
+            runtime_command.subcommand.as_deref(),
+            Some("create" | "run" | "delete")
+        );
+    if runtime_input.is_none() && !track_removal {
+        return Err(exec_oci_runtime(&runtime_path, &runtime_options));
     }
+    let exit_code = call_oci_runtime(&runtime_path, &runtime_options, runtime_input)?;
 
-    #[test]
-    fn returns_none_when_no_bundle_flag_present() {
-        let mut o = opts(&["start", "cid"]);
-        assert_eq!(get_bundle_path(&mut o), None);
+    // Free cpusets and forget runtimes once their containers no longer exist. A create or run may
+    // have failed because another container has the same ID, so only the CPUs reserved and the
+    // runtime recorded by this call are freed then.
+    if track_removal && let Some(container_id) = &runtime_command.container_id {
+        let subcommand = runtime_command.subcommand.as_deref();
+        let failed = matches!(subcommand, Some("create" | "run")) && exit_code != 0;
+        if failed && let Some(reservation) = cpuset_reservation.take() {
+            reservation.release()?;
+        }
+        let container_removed = match subcommand {
+            Some("delete") => exit_code == 0,
+            Some("run") => exit_code == 0 && !runtime_command.detach,
+            _ => false,
+        };
+        if container_removed && !cpuset_policy.is_empty() {
+            release_cpuset(container_id, &cpuset_policy.state_path)?;
+        }
+        if (container_removed && !runtime_routes.is_empty()) || (failed && route_recorded) {
+            forget_runtime(&runtime_state_path, container_id)?;
+        }
     }
+    std::process::exit(exit_code);
+}
 
-    #[test]
-    fn returns_none_for_empty_options() {
-        let mut o: Vec<String> = Vec::new();
-        assert_eq!(get_bundle_path(&mut o), None);
+/// Records a modifier as applied if it changed the config since the previous one, whose result is
+/// kept in `tracked_spec`.
+fn track_modifier(
+    applied_modifiers: &mut Vec<&'static str>,
+    tracked_spec: &mut serde_json::Value,
+    name: &'static str,
+    spec: &Spec,
+) -> Result<()> {
+    let current = serde_json::to_value(spec)?;
+    if current != *tracked_spec {
+        applied_modifiers.push(name);
+        *tracked_spec = current;
     }
+    Ok(())
+}
 
-    #[test]
-    fn returns_none_when_short_flag_has_no_following_arg() {
-        let mut o = opts(&["create", "-b"]);
-        assert_eq!(get_bundle_path(&mut o), None);
-    }
+/// Appends descriptions of the modifications made to a runtime call (e.g. `exec`) for a
+/// container to its debug log.
+fn append_debug_log(
+    debug_output_dir: &Path,
+    runtime_command: &RuntimeCommand,
+    kind: &str,
+    changes: &[String],
+) -> Result<()> {
+    fs::create_dir_all(debug_output_dir)?;
+    let container_id = runtime_command
+        .container_id
+        .as_deref()
+        .unwrap_or("unknown_container");
+    let log_filename = format!("{container_id}_{kind}_modifications.log");
+    let mut log = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(debug_output_dir.join(log_filename))?;
+    log.write_all(format!("{}\n", changes.join("\n")).as_bytes())?;
+    Ok(())
 }
@@ -0,0 +1,110 @@
+use crate::template::expand_template;
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{LinuxNamespace, LinuxNamespaceType, Spec};
+use std::path::Path;
+
+/// Makes the container join a pre-created network namespace instead of the one set up by the
+/// container engine.
+///
+/// The namespace name is produced by expanding `template` (see [`expand_template`]) and resolved
+/// within `netns_dir`, e.g. `/var/run/netns` for namespaces created with `ip netns add`. The
+/// namespace must already exist on the host, otherwise an error is returned so that the container
+/// is not silently created with the engine's default networking.
+pub(crate) fn modify_network_namespace(
+    spec: &mut Spec,
+    template: &str,
+    netns_dir: &Path,
+) -> Result<()> {
+    let name = expand_template(spec, template)
+        .with_context(|| "Unable to determine network namespace name")?;
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        bail!("Invalid network namespace name \"{name}\"");
+    }
+    let netns_path = netns_dir.join(&name);
+    if !netns_path.exists() {
+        bail!(
+            "Network namespace \"{name}\" does not exist at {}",
+            netns_path.display()
+        );
+    }
+
+    let Some(linux) = spec.linux() else {
+        bail!("Container config has no linux section");
+    };
+    let mut linux = linux.clone();
+    let mut namespaces = linux.namespaces().clone().unwrap_or_default();
+    let mut netns = LinuxNamespace::default();
+    netns.set_typ(LinuxNamespaceType::Network);
+    netns.set_path(Some(netns_path));
+    match namespaces
+        .iter_mut()
+        .find(|ns| ns.typ() == LinuxNamespaceType::Network)
+    {
+        Some(existing) => *existing = netns,
+        None => namespaces.push(netns),
+    }
+    linux.set_namespaces(Some(namespaces));
+    spec.set_linux(Some(linux));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn network_namespaces(spec: &Spec) -> Vec<LinuxNamespace> {
+        spec.linux()
+            .as_ref()
+            .unwrap()
+            .namespaces()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|ns| ns.typ() == LinuxNamespaceType::Network)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_existing_network_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("ctf-youki"), "").unwrap();
+        let mut spec = Spec::default();
+        modify_network_namespace(&mut spec, "ctf-{hostname}", dir.path()).unwrap();
+        let netns = network_namespaces(&spec);
+        assert_eq!(netns.len(), 1);
+        assert_eq!(
+            netns[0].path().as_deref(),
+            Some(dir.path().join("ctf-youki").as_path())
+        );
+    }
+
+    #[test]
+    fn adds_network_namespace_when_absent() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("ctf"), "").unwrap();
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_namespaces(Some(vec![]));
+        spec.set_linux(Some(linux));
+        modify_network_namespace(&mut spec, "ctf", dir.path()).unwrap();
+        assert_eq!(network_namespaces(&spec).len(), 1);
+    }
+
+    #[test]
+    fn missing_namespace_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec = Spec::default();
+        assert!(modify_network_namespace(&mut spec, "does-not-exist", dir.path()).is_err());
+        assert!(network_namespaces(&spec)[0].path().is_none());
+    }
+
+    #[test]
+    fn path_traversal_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spec = Spec::default();
+        assert!(modify_network_namespace(&mut spec, "../netns", dir.path()).is_err());
+        assert!(modify_network_namespace(&mut spec, "..", dir.path()).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use std::{
+    io::{self, Write},
+    mem,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Command, ExitStatus, Stdio},
+    ptr,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+/// Signals forwarded to the runtime while the interceptor waits for it.
+const FORWARDED_SIGNALS: &[libc::c_int] = &[
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGTERM,
+    libc::SIGWINCH,
+];
+
+/// PID of the runtime process, or 0 if it has not been started.
+static RUNTIME_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signum: libc::c_int) {
+    let pid = RUNTIME_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        // SAFETY: kill is async-signal-safe, and only signals the runtime process.
+        unsafe {
+            libc::kill(pid, signum);
+        }
+    }
+}
+
+/// Returns the set of forwarded signals.
+fn forwarded_signal_set() -> libc::sigset_t {
+    // SAFETY: the set is initialized by sigemptyset before use.
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signum in FORWARDED_SIGNALS {
+            libc::sigaddset(&mut set, signum);
+        }
+        set
+    }
+}
+
+/// Installs the handler forwarding signals to the runtime. Interrupted system calls are
+/// restarted, and the handler remains installed after a signal is delivered.
+fn install_signal_handlers() -> io::Result<()> {
+    for &signum in FORWARDED_SIGNALS {
+        // SAFETY: the handler only performs async-signal-safe operations.
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = forward_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Blocks (or unblocks) the forwarded signals in the calling thread.
+fn mask_forwarded_signals(how: libc::c_int) -> io::Result<()> {
+    let set = forwarded_signal_set();
+    // SAFETY: pthread_sigmask is async-signal-safe, and the set is initialized.
+    match unsafe { libc::pthread_sigmask(how, &set, ptr::null_mut()) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Replaces the interceptor process with the OCI runtime, passing along any runtime options.
+///
+/// The runtime keeps the interceptor's PID and receives any signals sent to it, so the
+/// interceptor is fully transparent to the caller. Only returns if the runtime cannot be
+/// executed.
+pub(crate) fn exec_oci_runtime(runtime_path: &str, options: &[String]) -> anyhow::Error {
+    let err = Command::new(runtime_path).args(options).exec();
+    anyhow::Error::new(err).context("Failed to execute underlying OCI runtime")
+}
+
+/// Converts an exit status into an exit code, using the shell's `128+signo` convention for
+/// processes which were killed by a signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signo)) => 128 + signo,
+        (None, None) => 1,
+    }
+}
+
+/// Calls the OCI runtime as a child process and waits for it to exit, for when the interceptor
+/// has work to do afterwards. Runtime options are passed along, `input` is written to the
+/// runtime's stdin if given, and signals sent to the interceptor are forwarded to the runtime.
+///
+/// Returns the runtime's exit code.
+pub(crate) fn call_oci_runtime(
+    runtime_path: &str,
+    options: &[String],
+    input: Option<Vec<u8>>,
+) -> Result<i32> {
+    let mut command = Command::new(runtime_path);
+    command.args(options);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    // SAFETY: the closure only performs async-signal-safe operations.
+    unsafe {
+        command.pre_exec(|| mask_forwarded_signals(libc::SIG_UNBLOCK));
+    }
+
+    // Signals are blocked until the runtime's PID is known, so that any received in the meantime
+    // are forwarded once they are unblocked, rather than dropped
+    install_signal_handlers().with_context(|| "Unable to install signal handlers")?;
+    mask_forwarded_signals(libc::SIG_BLOCK).with_context(|| "Unable to block signals")?;
+    let child = command.spawn();
+    if let Ok(child) = &child {
+        RUNTIME_PID.store(child.id() as i32, Ordering::SeqCst);
+    }
+    mask_forwarded_signals(libc::SIG_UNBLOCK).with_context(|| "Unable to unblock signals")?;
+    let mut child = child.with_context(|| "Failed to execute underlying OCI runtime")?;
+
+    if let Some(input) = input
+        && let Some(mut stdin) = child.stdin.take()
+    {
+        stdin
+            .write_all(&input)
+            .with_context(|| "Failed to write to OCI runtime process")?;
+    }
+    let status = child
+        .wait()
+        .with_context(|| "Failed to wait on OCI runtime process")?;
+    Ok(exit_code(status))
+}
+
+/// Calls the OCI runtime as a child process and captures its output, for when the interceptor
+/// modifies the output (e.g. of `features`). Runtime options are passed along.
+///
+/// Returns the runtime's exit code and stdout.
+pub(crate) fn query_oci_runtime(runtime_path: &str, options: &[String]) -> Result<(i32, Vec<u8>)> {
+    let output = Command::new(runtime_path)
+        .args(options)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| "Failed to execute underlying OCI runtime")?;
+    Ok((exit_code(output.status), output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_exit_statuses() {
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(9)), 137);
+        assert_eq!(exit_code(ExitStatus::from_raw(15)), 143);
+    }
+}
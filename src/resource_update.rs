@@ -0,0 +1,276 @@
+use crate::{
+    resources::{Resource, ResourcePolicy, clamp_resource_update, parse_bytes},
+    runtime_command::subcommand_options,
+};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::LinuxResources;
+use std::{fs, io::Read, path::Path};
+
+/// Options of runc's `update` command which set resources that may have ceilings.
+const UPDATE_OPTIONS: &[(&str, Resource)] = &[
+    ("--memory", Resource::Memory),
+    ("--memory-swap", Resource::MemorySwap),
+    ("--cpu-period", Resource::CpuPeriod),
+    ("--cpu-quota", Resource::CpuQuota),
+    ("--cpu-share", Resource::CpuShares),
+    ("--pids-limit", Resource::Pids),
+    ("--blkio-weight", Resource::BlkioWeight),
+];
+
+/// Clamps the resources set by a runtime `update` call to the configured ceilings.
+///
+/// Resources are read from the file given with `-r`/`--resources` (which is rewritten in place),
+/// from `input` if the file is `-`, or otherwise from individual options such as `--memory`
+/// (which are rewritten in `options`). Values which cannot be parsed are rejected, so that they
+/// cannot be used to bypass the ceilings.
+///
+/// The container's current quota is clamped if only the CPU period is being changed, in which case
+/// a `--cpu-quota` option is added.
+///
+/// Returns a description of each value which was changed, and the resources to pass to the
+/// runtime on stdin, if they were read from `input`.
+pub(crate) fn modify_resource_update(
+    options: &mut Vec<String>,
+    policy: &ResourcePolicy,
+    container: &LinuxResources,
+    mut input: impl Read,
+) -> Result<(Vec<String>, Option<Vec<u8>>)> {
+    let update_options = subcommand_options(options);
+
+    // runc ignores the individual options if a resources file is given
+    if let Some(path) = update_options
+        .iter()
+        .find(|o| o.name == "-r" || o.name == "--resources")
+        .and_then(|o| o.value.as_deref())
+    {
+        let contents = if path == "-" {
+            let mut contents = String::new();
+            input.read_to_string(&mut contents)?;
+            contents
+        } else {
+            fs::read_to_string(path)
+                .with_context(|| format!("Unable to read resources file {path}"))?
+        };
+        let mut resources: LinuxResources =
+            serde_json::from_str(&contents).with_context(|| "Unable to parse resources")?;
+        let changes = clamp_resource_update(&mut resources, policy, container);
+        let updated = serde_json::to_vec(&resources)?;
+        if path == "-" {
+            return Ok((changes, Some(updated)));
+        }
+        if !changes.is_empty() {
+            fs::write(Path::new(path), updated)
+                .with_context(|| format!("Unable to write resources file {path}"))?;
+        }
+        return Ok((changes, None));
+    }
+
+    let mut resources = LinuxResources::default();
+    let mut given = Vec::new();
+    for option in &update_options {
+        let Some((_, resource)) = UPDATE_OPTIONS.iter().find(|(name, _)| *name == option.name)
+        else {
+            continue;
+        };
+        let Some(value) = option.value.as_deref() else {
+            continue;
+        };
+        let parsed = match resource {
+            Resource::Memory | Resource::MemorySwap => parse_bytes(value),
+            _ => value.parse::<i64>().ok(),
+        };
+        let Some(parsed) = parsed else {
+            bail!("Unable to parse {} value {value}", option.name);
+        };
+        resource.set(&mut resources, parsed);
+        given.push((option, *resource, parsed));
+    }
+    let changes = clamp_resource_update(&mut resources, policy, container);
+    for (option, resource, original) in &given {
+        if let Some(value) = resource.get(&resources)
+            && value != *original
+        {
+            option.set_value(options, &value.to_string());
+        }
+    }
+    // Added last, as it shifts the positions of later options
+    if let Some(quota) = Resource::CpuQuota.get(&resources)
+        && !given.iter().any(|(_, r, _)| *r == Resource::CpuQuota)
+        && let Some((period_option, _, _)) =
+            given.iter().find(|(_, r, _)| *r == Resource::CpuPeriod)
+    {
+        period_option.insert_after(options, &format!("--cpu-quota={quota}"));
+    }
+    Ok((changes, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::parse_resource_limit;
+
+    fn policy(ceilings: &[&str]) -> ResourcePolicy {
+        ResourcePolicy {
+            defaults: Vec::new(),
+            ceilings: ceilings
+                .iter()
+                .map(|c| parse_resource_limit(c).unwrap())
+                .collect(),
+        }
+    }
+
+    fn options(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// The resources of a container with the given CPU period and quota.
+    fn container(period: i64, quota: i64) -> LinuxResources {
+        let mut resources = LinuxResources::default();
+        Resource::CpuPeriod.set(&mut resources, period);
+        Resource::CpuQuota.set(&mut resources, quota);
+        resources
+    }
+
+    #[test]
+    fn clamps_update_options() {
+        let policy = policy(&["memory=1g", "pids=100", "cpu-quota=50000"]);
+        let mut args = options(&[
+            "update",
+            "--memory",
+            "4g",
+            "--pids-limit=-1",
+            "--cpu-quota",
+            "50000",
+            "--cpu-share",
+            "512",
+            "cid",
+        ]);
+        let (changes, stdin) = modify_resource_update(
+            &mut args,
+            &policy,
+            &container(50_000, 25_000),
+            "".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(stdin, None);
+        assert_eq!(
+            args,
+            options(&[
+                "update",
+                "--memory",
+                "1073741824",
+                "--pids-limit=100",
+                "--cpu-quota",
+                "25000",
+                "--cpu-share",
+                "512",
+                "cid",
+            ])
+        );
+
+        // Sizes are parsed as by docker
+        let mut args = options(&["update", "--memory", "512MiB", "cid"]);
+        let (changes, _) =
+            modify_resource_update(&mut args, &policy, &Default::default(), "".as_bytes()).unwrap();
+        assert!(changes.is_empty());
+        let mut args = options(&["update", "--memory", "1.5g", "cid"]);
+        modify_resource_update(&mut args, &policy, &Default::default(), "".as_bytes()).unwrap();
+        assert_eq!(args[2], "1073741824");
+        let mut args = options(&["update", "--memory", "1.5x", "cid"]);
+        assert!(
+            modify_resource_update(&mut args, &policy, &Default::default(), "".as_bytes()).is_err()
+        );
+    }
+
+    #[test]
+    fn clamps_current_quota_to_updated_period() {
+        let policy = policy(&["cpu-quota=50000"]);
+        let mut args = options(&["update", "--cpu-period", "10000", "cid"]);
+        let (changes, _) = modify_resource_update(
+            &mut args,
+            &policy,
+            &container(100_000, 50_000),
+            "".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            ["cpu-quota: clamped current 50000 to 5000 for cpu-period 10000"]
+        );
+        assert_eq!(
+            args,
+            options(&["update", "--cpu-period", "10000", "--cpu-quota=5000", "cid"])
+        );
+
+        // Longer periods leave the current quota within the ceiling
+        let mut args = options(&["update", "--cpu-period", "1000000", "cid"]);
+        let (changes, _) = modify_resource_update(
+            &mut args,
+            &policy,
+            &container(100_000, 50_000),
+            "".as_bytes(),
+        )
+        .unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(args.len(), 4);
+    }
+
+    #[test]
+    fn clamps_update_resources() {
+        let policy = policy(&["memory=1g"]);
+        let mut args = options(&["update", "--resources", "-", "--memory", "8g", "cid"]);
+        let (changes, stdin) = modify_resource_update(
+            &mut args,
+            &policy,
+            &Default::default(),
+            r#"{"memory": {"limit": 4294967296, "swap": 4294967296}, "pids": {"limit": 0}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(changes, ["memory: clamped 4294967296 to 1073741824"]);
+        let resources: serde_json::Value = serde_json::from_slice(&stdin.unwrap()).unwrap();
+        assert_eq!(resources["memory"]["limit"], 1073741824);
+        assert_eq!(resources["memory"]["swap"], 4294967296u64);
+        assert_eq!(args[4], "8g");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resources.json");
+        fs::write(&path, r#"{"memory": {"limit": -1}}"#).unwrap();
+        let mut args = options(&["update", "-r"]);
+        args.push(path.to_string_lossy().into_owned());
+        args.push(String::from("cid"));
+        let (changes, _) =
+            modify_resource_update(&mut args, &policy, &Default::default(), "".as_bytes()).unwrap();
+        assert_eq!(changes, ["memory: clamped unlimited to 1073741824"]);
+        let resources: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(resources["memory"]["limit"], 1073741824);
+    }
+
+    #[test]
+    fn removes_unified_update_entries_bypassing_ceilings() {
+        let policy = policy(&["memory=1g", "pids=100"]);
+        let mut args = options(&["update", "--resources", "-", "cid"]);
+        let (changes, stdin) = modify_resource_update(
+            &mut args,
+            &policy,
+            &Default::default(),
+            r#"{"unified": {"memory.max": "max", "pids.max": "max", "memory.high": "512M"}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                "unified: removed memory.max=max, which would bypass a resource ceiling",
+                "unified: removed pids.max=max, which would bypass a resource ceiling",
+            ]
+        );
+        let resources: serde_json::Value = serde_json::from_slice(&stdin.unwrap()).unwrap();
+        assert_eq!(
+            resources["unified"],
+            serde_json::json!({"memory.high": "512M"})
+        );
+    }
+}
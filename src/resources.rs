@@ -0,0 +1,546 @@
+use crate::cgroup_unified::conflicting_unified_keys;
+use anyhow::{Context, bail};
+use oci_spec::runtime::{LinuxResources, Spec};
+
+/// The kernel's default CPU CFS period, in microseconds.
+const DEFAULT_CPU_PERIOD: i64 = 100_000;
+
+/// A cgroup resource which can be given a default and maximum value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resource {
+    /// Memory limit, in bytes.
+    Memory,
+    /// Total memory and swap limit, in bytes.
+    MemorySwap,
+    /// CPU CFS period, in microseconds.
+    CpuPeriod,
+    /// CPU CFS quota, in microseconds per period.
+    CpuQuota,
+    /// Relative CPU weight.
+    CpuShares,
+    /// Maximum number of processes.
+    Pids,
+    /// Relative block IO weight (10-1000).
+    BlkioWeight,
+}
+
+impl Resource {
+    /// All resources, in the order in which they are processed.
+    pub(crate) const ALL: [Resource; 7] = [
+        Resource::Memory,
+        Resource::MemorySwap,
+        Resource::CpuPeriod,
+        Resource::CpuQuota,
+        Resource::CpuShares,
+        Resource::Pids,
+        Resource::BlkioWeight,
+    ];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Resource::Memory => "memory",
+            Resource::MemorySwap => "memory-swap",
+            Resource::CpuPeriod => "cpu-period",
+            Resource::CpuQuota => "cpu-quota",
+            Resource::CpuShares => "cpu-shares",
+            Resource::Pids => "pids",
+            Resource::BlkioWeight => "blkio-weight",
+        }
+    }
+
+    /// Returns whether the resource is a relative weight, rather than a limit.
+    fn is_weight(&self) -> bool {
+        matches!(self, Resource::CpuShares | Resource::BlkioWeight)
+    }
+
+    /// Returns the current value of the resource, or `None` if it is unset or unlimited.
+    pub(crate) fn get(&self, resources: &LinuxResources) -> Option<i64> {
+        // Negative values (and zero, for pids and weights) mean "unlimited" or "unset"
+        self.raw(resources).filter(|v| *v > 0)
+    }
+
+    /// Returns the value of the resource as given, including negative and zero values.
+    fn raw(&self, resources: &LinuxResources) -> Option<i64> {
+        match self {
+            Resource::Memory => resources.memory().and_then(|m| m.limit()),
+            Resource::MemorySwap => resources.memory().and_then(|m| m.swap()),
+            Resource::CpuPeriod => resources
+                .cpu()
+                .as_ref()
+                .and_then(|c| c.period())
+                .and_then(|v| i64::try_from(v).ok()),
+            Resource::CpuQuota => resources.cpu().as_ref().and_then(|c| c.quota()),
+            Resource::CpuShares => resources
+                .cpu()
+                .as_ref()
+                .and_then(|c| c.shares())
+                .and_then(|v| i64::try_from(v).ok()),
+            Resource::Pids => resources.pids().map(|p| p.limit()),
+            Resource::BlkioWeight => resources
+                .block_io()
+                .as_ref()
+                .and_then(|b| b.weight())
+                .map(i64::from),
+        }
+    }
+
+    /// Sets the value of the resource.
+    pub(crate) fn set(&self, resources: &mut LinuxResources, value: i64) {
+        match self {
+            Resource::Memory | Resource::MemorySwap => {
+                let mut memory = resources.memory().unwrap_or_default();
+                match self {
+                    Resource::Memory => memory.set_limit(Some(value)),
+                    _ => memory.set_swap(Some(value)),
+                };
+                resources.set_memory(Some(memory));
+            }
+            Resource::CpuPeriod | Resource::CpuQuota | Resource::CpuShares => {
+                let mut cpu = resources.cpu().clone().unwrap_or_default();
+                match self {
+                    Resource::CpuPeriod => cpu.set_period(u64::try_from(value).ok()),
+                    Resource::CpuQuota => cpu.set_quota(Some(value)),
+                    _ => cpu.set_shares(u64::try_from(value).ok()),
+                };
+                resources.set_cpu(Some(cpu));
+            }
+            Resource::Pids => {
+                let mut pids = resources.pids().unwrap_or_default();
+                pids.set_limit(value);
+                resources.set_pids(Some(pids));
+            }
+            Resource::BlkioWeight => {
+                let mut block_io = resources.block_io().clone().unwrap_or_default();
+                block_io.set_weight(u16::try_from(value).ok());
+                resources.set_block_io(Some(block_io));
+            }
+        }
+    }
+}
+
+/// A default or maximum value for a resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ResourceLimit {
+    resource: Resource,
+    value: i64,
+}
+
+/// Parses a byte size as accepted by `docker run --memory` (go-units' `RAMInBytes`): a decimal
+/// number, optionally followed by a space and a binary unit (`k`, `m`, `g`, `t` or `p`, in any
+/// case, optionally followed by `i` and/or `b`), e.g. `512MiB`, `1.5g` or `1 GB`. Fractional
+/// sizes are truncated to whole bytes.
+pub(crate) fn parse_bytes(value: &str) -> Option<i64> {
+    let lower = value.to_ascii_lowercase();
+    let rest = lower.strip_suffix('b').unwrap_or(&lower);
+    let rest = rest.strip_suffix('i').unwrap_or(rest);
+    let (number, multiplier) = match rest.char_indices().last() {
+        Some((i, 'k')) => (&rest[..i], 1i64 << 10),
+        Some((i, 'm')) => (&rest[..i], 1 << 20),
+        Some((i, 'g')) => (&rest[..i], 1 << 30),
+        Some((i, 't')) => (&rest[..i], 1 << 40),
+        Some((i, 'p')) => (&rest[..i], 1 << 50),
+        _ => (rest, 1),
+    };
+    let number = number.strip_suffix(' ').unwrap_or(number);
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match number.split_once('.') {
+        None if is_digits(number) => number.parse::<i64>().ok()?.checked_mul(multiplier),
+        Some((whole, fraction)) if is_digits(whole) && is_digits(fraction) => {
+            let size = number.parse::<f64>().ok()? * multiplier as f64;
+            (size < i64::MAX as f64).then_some(size as i64)
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_resource_limit(value: &str) -> Result<ResourceLimit, anyhow::Error> {
+    let err_msg = "resource limits must be in NAME=VALUE format";
+    let (name, value) = value.split_once('=').context(err_msg)?;
+    let Some(resource) = Resource::ALL.into_iter().find(|r| r.name() == name) else {
+        let names: Vec<&str> = Resource::ALL.iter().map(|r| r.name()).collect();
+        bail!(
+            "unknown resource {name} (expected one of {})",
+            names.join(", ")
+        );
+    };
+    let parsed = match resource {
+        Resource::Memory | Resource::MemorySwap => parse_bytes(value),
+        _ => value.parse::<i64>().ok(),
+    };
+    let value = match parsed {
+        Some(v) if v > 0 => v,
+        _ => bail!("invalid value for {name}: {value}"),
+    };
+    if resource == Resource::BlkioWeight && !(10..=1000).contains(&value) {
+        bail!("blkio-weight must be between 10 and 1000");
+    }
+    Ok(ResourceLimit { resource, value })
+}
+
+/// Default and maximum values for container resources.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResourcePolicy {
+    /// Values used for resources which the container does not limit.
+    pub(crate) defaults: Vec<ResourceLimit>,
+    /// Maximum values, which also apply to resources the container does not limit.
+    pub(crate) ceilings: Vec<ResourceLimit>,
+}
+
+impl ResourcePolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.defaults.is_empty() && self.ceilings.is_empty()
+    }
+
+    fn find(limits: &[ResourceLimit], resource: Resource) -> Option<i64> {
+        limits
+            .iter()
+            .rev()
+            .find(|l| l.resource == resource)
+            .map(|l| l.value)
+    }
+
+    pub(crate) fn default(&self, resource: Resource) -> Option<i64> {
+        Self::find(&self.defaults, resource)
+    }
+
+    pub(crate) fn ceiling(&self, resource: Resource) -> Option<i64> {
+        Self::find(&self.ceilings, resource)
+    }
+
+    /// Returns the maximum value of a resource, scaling the CPU quota ceiling to the given CPU
+    /// period.
+    fn scaled_ceiling(&self, resource: Resource, period: Option<i64>) -> Option<i64> {
+        let ceiling = self.ceiling(resource)?;
+        if resource == Resource::CpuQuota {
+            let period = period.unwrap_or(DEFAULT_CPU_PERIOD);
+            return Some(ceiling.saturating_mul(period) / DEFAULT_CPU_PERIOD);
+        }
+        Some(ceiling)
+    }
+}
+
+/// Fills in default values for resources which the container does not limit, and clamps values
+/// exceeding the configured ceilings. Ceilings on weights (`cpu-shares` and `blkio-weight`) only
+/// apply to weights which the container sets.
+///
+/// The CPU quota ceiling is expressed relative to the default CPU period of 100ms, and is scaled
+/// to the container's CPU period so that it limits the fraction of CPU time available.
+///
+/// Returns a description of each value which was changed.
+pub(crate) fn modify_resources(spec: &mut Spec, policy: &ResourcePolicy) -> Vec<String> {
+    let mut changes = Vec::new();
+    let Some(linux) = spec.linux() else {
+        return changes;
+    };
+    let mut linux = linux.clone();
+    let mut resources = linux.resources().clone().unwrap_or_default();
+
+    for resource in Resource::ALL {
+        // Swap can only be limited along with memory, so a memory limit is set to enforce a swap
+        // ceiling, and a swap default is only used if there is a memory limit
+        if resource == Resource::MemorySwap && Resource::Memory.get(&resources).is_none() {
+            let Some(ceiling) = policy.ceiling(Resource::MemorySwap) else {
+                continue;
+            };
+            Resource::Memory.set(&mut resources, ceiling);
+            changes.push(format!(
+                "memory: limited to {ceiling} to enforce the memory-swap ceiling"
+            ));
+        }
+        let mut current = resource.get(&resources);
+        if current.is_none()
+            && let Some(default) = policy.default(resource)
+        {
+            resource.set(&mut resources, default);
+            changes.push(format!("{}: defaulted to {default}", resource.name()));
+            current = Some(default);
+        }
+        let period = Resource::CpuPeriod.get(&resources);
+        if let Some(ceiling) = policy.scaled_ceiling(resource, period) {
+            match current {
+                Some(value) if value <= ceiling => {}
+                Some(value) => {
+                    resource.set(&mut resources, ceiling);
+                    changes.push(format!("{}: clamped {value} to {ceiling}", resource.name()));
+                }
+                // Unset weights are left at the runtime's default, rather than set to the ceiling
+                None if resource.is_weight() => {}
+                None => {
+                    resource.set(&mut resources, ceiling);
+                    changes.push(format!(
+                        "{}: clamped unlimited to {ceiling}",
+                        resource.name()
+                    ));
+                }
+            }
+        }
+    }
+
+    changes.extend(raise_memory_swap(&mut resources));
+    linux.set_resources(Some(resources));
+    spec.set_linux(Some(linux));
+    changes
+}
+
+/// Raises memory+swap to the memory limit if it is lower, as it may not be.
+fn raise_memory_swap(resources: &mut LinuxResources) -> Option<String> {
+    let limit = Resource::Memory.get(resources)?;
+    let swap = Resource::MemorySwap.get(resources)?;
+    if swap >= limit {
+        return None;
+    }
+    Resource::MemorySwap.set(resources, limit);
+    Some(format!(
+        "memory-swap: raised {swap} to memory limit {limit}"
+    ))
+}
+
+/// Clamps the resources set by a runtime `update` call to the configured ceilings.
+///
+/// Unlike at creation, only resources which are being changed are considered: zero values leave
+/// the current limit in place, while negative (unlimited) values are clamped. The CPU quota
+/// ceiling is scaled to the CPU period being set, or otherwise to the container's current period
+/// (from its `container` resources), and the container's current quota is clamped if only the
+/// period is being changed. Unified (cgroup v2) entries equivalent to a resource with a ceiling are
+/// removed, as they would otherwise bypass it.
+///
+/// Returns a description of each value which was changed.
+pub(crate) fn clamp_resource_update(
+    resources: &mut LinuxResources,
+    policy: &ResourcePolicy,
+    container: &LinuxResources,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    // A shorter period would let the current quota exceed the fraction of CPU time allowed
+    if let Some(period) = Resource::CpuPeriod.get(resources)
+        && matches!(Resource::CpuQuota.raw(resources), None | Some(0))
+        && let Some(quota) = Resource::CpuQuota.get(container)
+        && let Some(ceiling) = policy.scaled_ceiling(Resource::CpuQuota, Some(period))
+        && quota > ceiling
+    {
+        Resource::CpuQuota.set(resources, ceiling);
+        changes.push(format!(
+            "cpu-quota: clamped current {quota} to {ceiling} for cpu-period {period}"
+        ));
+    }
+
+    let container_period = Resource::CpuPeriod.get(container);
+    for resource in Resource::ALL {
+        let period = Resource::CpuPeriod.get(resources).or(container_period);
+        let Some(ceiling) = policy.scaled_ceiling(resource, period) else {
+            continue;
+        };
+        match resource.raw(resources) {
+            Some(value) if value > ceiling => {
+                resource.set(resources, ceiling);
+                changes.push(format!("{}: clamped {value} to {ceiling}", resource.name()));
+            }
+            Some(value) if value < 0 => {
+                resource.set(resources, ceiling);
+                changes.push(format!(
+                    "{}: clamped unlimited to {ceiling}",
+                    resource.name()
+                ));
+            }
+            _ => {}
+        }
+    }
+    changes.extend(raise_memory_swap(resources));
+
+    if let Some(unified) = resources.unified() {
+        let mut ceilinged = LinuxResources::default();
+        for resource in Resource::ALL {
+            if let Some(ceiling) = policy.ceiling(resource) {
+                resource.set(&mut ceilinged, ceiling);
+            }
+        }
+        let mut unified = unified.clone();
+        for key in conflicting_unified_keys(&ceilinged) {
+            if let Some(value) = unified.remove(key) {
+                changes.push(format!(
+                    "unified: removed {key}={value}, which would bypass a resource ceiling"
+                ));
+            }
+        }
+        resources.set_unified(Some(unified));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::runtime::{LinuxBlockIo, LinuxCpu, LinuxMemory, LinuxPids};
+
+    fn policy(defaults: &[&str], ceilings: &[&str]) -> ResourcePolicy {
+        ResourcePolicy {
+            defaults: defaults
+                .iter()
+                .map(|v| parse_resource_limit(v).unwrap())
+                .collect(),
+            ceilings: ceilings
+                .iter()
+                .map(|v| parse_resource_limit(v).unwrap())
+                .collect(),
+        }
+    }
+
+    fn spec_with_resources(resources: LinuxResources) -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    fn resources(spec: &Spec) -> LinuxResources {
+        spec.linux().as_ref().unwrap().resources().clone().unwrap()
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!(parse_bytes("1024"), Some(1024));
+        assert_eq!(parse_bytes("4g"), Some(4 << 30));
+        assert_eq!(parse_bytes("512MiB"), Some(512 << 20));
+        assert_eq!(parse_bytes("1 GB"), Some(1 << 30));
+        assert_eq!(parse_bytes("1.5k"), Some(1536));
+        assert_eq!(parse_bytes("2P"), Some(2 << 50));
+        assert_eq!(parse_bytes("1.5.2g"), None);
+        assert_eq!(parse_bytes(".5g"), None);
+        assert_eq!(parse_bytes("-1g"), None);
+        assert_eq!(parse_bytes("1  g"), None);
+        assert_eq!(parse_bytes("g"), None);
+        assert_eq!(parse_bytes("8388608p"), None);
+    }
+
+    #[test]
+    fn parses_resource_limits() {
+        assert_eq!(
+            parse_resource_limit("memory=512m").unwrap(),
+            ResourceLimit {
+                resource: Resource::Memory,
+                value: 512 << 20
+            }
+        );
+        assert_eq!(
+            parse_resource_limit("memory-swap=1GB").unwrap().value,
+            1 << 30
+        );
+        assert_eq!(parse_resource_limit("memory=4096").unwrap().value, 4096);
+        assert_eq!(parse_resource_limit("pids=100").unwrap().value, 100);
+        assert!(parse_resource_limit("pids=100k").is_err());
+        assert!(parse_resource_limit("pids=-1").is_err());
+        assert!(parse_resource_limit("blkio-weight=5").is_err());
+        assert!(parse_resource_limit("swap=1g").is_err());
+        assert!(parse_resource_limit("memory").is_err());
+    }
+
+    #[test]
+    fn fills_defaults() {
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let policy = policy(&["memory=1g", "pids=256", "cpu-shares=512"], &[]);
+        let changes = modify_resources(&mut spec, &policy);
+        let resources = resources(&spec);
+        assert_eq!(resources.memory().unwrap().limit(), Some(1 << 30));
+        assert_eq!(resources.pids().unwrap().limit(), 256);
+        assert_eq!(resources.cpu().as_ref().unwrap().shares(), Some(512));
+        assert_eq!(changes.len(), 3);
+    }
+
+    #[test]
+    fn keeps_user_values_over_defaults() {
+        let mut memory = LinuxMemory::default();
+        memory.set_limit(Some(64 << 20));
+        let mut resources = LinuxResources::default();
+        resources.set_memory(Some(memory));
+        let mut spec = spec_with_resources(resources);
+        let changes = modify_resources(&mut spec, &policy(&["memory=1g"], &[]));
+        assert_eq!(
+            self::resources(&spec).memory().unwrap().limit(),
+            Some(64 << 20)
+        );
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn clamps_to_ceilings() {
+        let mut memory = LinuxMemory::default();
+        memory.set_limit(Some(64 << 30));
+        memory.set_swap(Some(128 << 30));
+        let mut pids = LinuxPids::default();
+        pids.set_limit(-1);
+        let mut block_io = LinuxBlockIo::default();
+        block_io.set_weight(Some(100));
+        let mut resources = LinuxResources::default();
+        resources.set_memory(Some(memory));
+        resources.set_pids(Some(pids));
+        resources.set_block_io(Some(block_io));
+        let mut spec = spec_with_resources(resources);
+
+        let policy = policy(
+            &[],
+            &[
+                "memory=2g",
+                "memory-swap=1g",
+                "pids=512",
+                "blkio-weight=500",
+            ],
+        );
+        let changes = modify_resources(&mut spec, &policy);
+        let resources = self::resources(&spec);
+        assert_eq!(resources.memory().unwrap().limit(), Some(2 << 30));
+        // Swap is clamped, but may not be lower than the memory limit
+        assert_eq!(resources.memory().unwrap().swap(), Some(2 << 30));
+        assert_eq!(resources.pids().unwrap().limit(), 512);
+        assert_eq!(resources.block_io().as_ref().unwrap().weight(), Some(100));
+        assert!(changes.contains(&String::from("memory: clamped 68719476736 to 2147483648")));
+        assert!(changes.contains(&String::from("pids: clamped unlimited to 512")));
+    }
+
+    #[test]
+    fn scales_cpu_quota_ceiling_to_period() {
+        let mut cpu = LinuxCpu::default();
+        cpu.set_period(Some(10_000));
+        cpu.set_quota(Some(100_000));
+        let mut resources = LinuxResources::default();
+        resources.set_cpu(Some(cpu));
+        let mut spec = spec_with_resources(resources);
+        modify_resources(&mut spec, &policy(&[], &["cpu-quota=50000"]));
+        assert_eq!(
+            self::resources(&spec).cpu().as_ref().unwrap().quota(),
+            Some(5_000)
+        );
+    }
+
+    #[test]
+    fn leaves_unset_weights() {
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let changes = modify_resources(
+            &mut spec,
+            &policy(&[], &["cpu-shares=512", "blkio-weight=500"]),
+        );
+        assert!(changes.is_empty());
+        let resources = self::resources(&spec);
+        assert_eq!(resources.cpu().as_ref().and_then(|c| c.shares()), None);
+        assert_eq!(resources.block_io().as_ref().and_then(|b| b.weight()), None);
+    }
+
+    #[test]
+    fn limits_swap_only_with_memory() {
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let changes = modify_resources(&mut spec, &policy(&["memory-swap=2g"], &[]));
+        assert!(changes.is_empty());
+        assert_eq!(self::resources(&spec).memory(), &None);
+
+        let mut spec = spec_with_resources(LinuxResources::default());
+        let changes = modify_resources(&mut spec, &policy(&[], &["memory-swap=2g"]));
+        let memory = self::resources(&spec).memory().unwrap();
+        assert_eq!(memory.limit(), Some(2 << 30));
+        assert_eq!(memory.swap(), Some(2 << 30));
+        assert_eq!(
+            changes[0],
+            "memory: limited to 2147483648 to enforce the memory-swap ceiling"
+        );
+    }
+}
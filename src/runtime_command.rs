@@ -0,0 +1,555 @@
+use crate::annotations::{ANNOTATION_PREFIX, CONFIG_ANNOTATION};
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::Spec;
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Global runtime options which take a value, as accepted by runc, crun and youki.
+const GLOBAL_VALUE_OPTIONS: &[&str] = &[
+    "--root",
+    "--log",
+    "--log-format",
+    "--log-level",
+    "--criu",
+    "--rootless",
+    "--cgroup-manager",
+];
+
+/// Returns the options of a subcommand which take a value.
+///
+/// Options of unknown subcommands are assumed not to take values.
+fn subcommand_value_options(subcommand: &str) -> &'static [&'static str] {
+    match subcommand {
+        "create" | "run" => &[
+            "-b",
+            "--bundle",
+            "-f",
+            "--config",
+            "--console-socket",
+            "--pid-file",
+            "--preserve-fds",
+        ],
+        "exec" => &[
+            "--console-socket",
+            "--pidfd-socket",
+            "--cwd",
+            "-e",
+            "--env",
+            "-p",
+            "--process",
+            "--pid-file",
+            "--process-label",
+            "--apparmor",
+            "-u",
+            "--user",
+            "-g",
+            "--additional-gids",
+            "-c",
+            "--cap",
+            "--preserve-fds",
+            "--cgroup",
+        ],
+        "update" => &[
+            "-r",
+            "--resources",
+            "--blkio-weight",
+            "--cpu-period",
+            "--cpu-quota",
+            "--cpu-share",
+            "--cpu-rt-period",
+            "--cpu-rt-runtime",
+            "--cpu-idle",
+            "--cpuset-cpus",
+            "--cpuset-mems",
+            "--kernel-memory",
+            "--kernel-memory-tcp",
+            "--memory",
+            "--memory-reservation",
+            "--memory-swap",
+            "--pids-limit",
+            "--l3-cache-schema",
+            "--mem-bw-schema",
+        ],
+        "restore" => &[
+            "-b",
+            "--bundle",
+            "--console-socket",
+            "--pid-file",
+            "--image-path",
+            "--work-path",
+            "--parent-path",
+            "--manage-cgroups-mode",
+            "--empty-ns",
+            "--lsm-profile",
+            "--lsm-mount-context",
+        ],
+        "checkpoint" => &[
+            "--image-path",
+            "--work-path",
+            "--parent-path",
+            "--page-server",
+            "--manage-cgroups-mode",
+            "--empty-ns",
+        ],
+        "events" => &["--interval"],
+        "list" | "ps" => &["-f", "--format"],
+        _ => &[],
+    }
+}
+
+/// A parsed invocation of an OCI runtime, following runc's command-line interface.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct RuntimeCommand {
+    /// `--root`: the directory in which the runtime stores container state.
+    pub(crate) root: Option<PathBuf>,
+    /// `--log`: the runtime's log file.
+    pub(crate) log: Option<PathBuf>,
+    /// `--log-format`: the format of the runtime's log file (`text` or `json`).
+    pub(crate) log_format: Option<String>,
+    /// `--systemd-cgroup`: whether cgroups are managed by systemd.
+    pub(crate) systemd_cgroup: bool,
+    pub(crate) subcommand: Option<String>,
+    pub(crate) container_id: Option<String>,
+    /// `-b`/`--bundle`: the container's bundle directory, as given.
+    pub(crate) bundle: Option<PathBuf>,
+    /// `-f`/`--config` (crun only): the container's config file, if not `config.json`.
+    pub(crate) config: Option<PathBuf>,
+    /// `-p`/`--process` (`exec` only): the file containing the process to execute.
+    pub(crate) process: Option<PathBuf>,
+    /// `-c`/`--cap` (`exec` only): capabilities added to the process to execute.
+    pub(crate) caps: Vec<String>,
+    /// `--pid-file`: the file to which the container's PID is written.
+    pub(crate) pid_file: Option<PathBuf>,
+    /// `--console-socket`: the socket to which the container's pseudoterminal is sent.
+    pub(crate) console_socket: Option<PathBuf>,
+    /// `-d`/`--detach`: whether `run` or `exec` returns once the process has started.
+    pub(crate) detach: bool,
+}
+
+impl RuntimeCommand {
+    /// Returns the path of the container's bundle directory, resolved the same way as by the
+    /// runtime.
+    ///
+    /// Relative bundle paths are resolved against the working directory `cwd`, and `create`, `run`
+    /// and `restore` use the working directory itself if no bundle is given.
+    pub(crate) fn bundle_path(&self, cwd: &Path) -> Option<PathBuf> {
+        match (&self.bundle, self.subcommand.as_deref()) {
+            (Some(bundle), _) => Some(cwd.join(bundle)),
+            (None, Some("create" | "run" | "restore")) => Some(cwd.to_path_buf()),
+            (None, _) => None,
+        }
+    }
+
+    /// Returns the path of the container's config file, if any.
+    ///
+    /// crun resolves relative config paths against the bundle directory.
+    pub(crate) fn config_path(&self, cwd: &Path) -> Option<PathBuf> {
+        let config = self.config.as_deref().unwrap_or(Path::new("config.json"));
+        self.bundle_path(cwd).map(|bundle| bundle.join(config))
+    }
+
+    /// Returns the path of the file containing the process to execute, if any.
+    pub(crate) fn process_path(&self, cwd: &Path) -> Option<PathBuf> {
+        self.process.as_ref().map(|process| cwd.join(process))
+    }
+}
+
+/// An option in a runtime invocation.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RuntimeOption {
+    /// Whether the option precedes the subcommand.
+    global: bool,
+    pub(crate) name: String,
+    pub(crate) value: Option<String>,
+    /// The index of the argument containing the value, and whether it is given in `--name=value`
+    /// form.
+    value_position: Option<(usize, bool)>,
+}
+
+impl RuntimeOption {
+    /// Replaces the option's value in the runtime options it was parsed from.
+    pub(crate) fn set_value(&self, options: &mut [String], value: &str) {
+        match self.value_position {
+            Some((index, true)) => options[index] = format!("{}={value}", self.name),
+            Some((index, false)) => options[index] = String::from(value),
+            None => {}
+        }
+    }
+
+    /// Inserts an argument following the option's value in the runtime options it was parsed
+    /// from.
+    pub(crate) fn insert_after(&self, options: &mut Vec<String>, arg: &str) {
+        if let Some((index, _)) = self.value_position {
+            options.insert(index + 1, String::from(arg));
+        }
+    }
+}
+
+/// The components of a runtime invocation, in the order given.
+#[derive(Debug, Default)]
+struct Invocation {
+    options: Vec<RuntimeOption>,
+    subcommand: Option<String>,
+    container_id: Option<String>,
+}
+
+/// Splits the trailing runtime options into options, the subcommand and the container ID.
+///
+/// Global options precede the subcommand, and subcommand options precede the container ID, which
+/// is the first positional argument following the subcommand. Any further arguments (e.g. the
+/// command given to `exec`, or the signal given to `kill`) are ignored.
+fn split_invocation(options: &[String]) -> Invocation {
+    let mut invocation = Invocation::default();
+    let mut options = options.iter().enumerate();
+    while let Some((index, option)) = options.next() {
+        if option == "--" {
+            if invocation.subcommand.is_some() {
+                invocation.container_id = options.next().map(|(_, id)| id.clone());
+                break;
+            }
+            continue;
+        }
+        if !option.starts_with('-') || option == "-" {
+            match invocation.subcommand {
+                None => invocation.subcommand = Some(option.clone()),
+                Some(_) => {
+                    invocation.container_id = Some(option.clone());
+                    break;
+                }
+            }
+            continue;
+        }
+
+        let value_options = match &invocation.subcommand {
+            None => GLOBAL_VALUE_OPTIONS,
+            Some(subcommand) => subcommand_value_options(subcommand),
+        };
+        let (name, value, value_position) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value), Some((index, true))),
+            None if value_options.contains(&option.as_str()) => match options.next() {
+                Some((index, value)) => {
+                    (option.as_str(), Some(value.as_str()), Some((index, false)))
+                }
+                None => (option.as_str(), None, None),
+            },
+            None => (option.as_str(), None, None),
+        };
+        invocation.options.push(RuntimeOption {
+            global: invocation.subcommand.is_none(),
+            name: String::from(name),
+            value: value.map(String::from),
+            value_position,
+        });
+    }
+    invocation
+}
+
+/// Returns the options given to the subcommand.
+pub(crate) fn subcommand_options(options: &[String]) -> Vec<RuntimeOption> {
+    split_invocation(options)
+        .options
+        .into_iter()
+        .filter(|option| !option.global)
+        .collect()
+}
+
+/// Parses the trailing runtime options into a typed invocation.
+///
+/// Invalid invocations are parsed leniently, leaving the runtime to report errors.
+pub(crate) fn parse_runtime_command(options: &[String]) -> RuntimeCommand {
+    let invocation = split_invocation(options);
+    let mut command = RuntimeCommand {
+        subcommand: invocation.subcommand,
+        container_id: invocation.container_id,
+        ..RuntimeCommand::default()
+    };
+    for option in invocation.options {
+        let value = option.value.as_deref();
+        let subcommand = if option.global {
+            None
+        } else {
+            command.subcommand.as_deref()
+        };
+        match (option.global, subcommand, option.name.as_str()) {
+            (true, _, "--root") => command.root = value.map(PathBuf::from),
+            (true, _, "--log") => command.log = value.map(PathBuf::from),
+            (true, _, "--log-format") => command.log_format = value.map(String::from),
+            (true, _, "--systemd-cgroup") => command.systemd_cgroup = value != Some("false"),
+            (false, Some("create" | "run" | "restore"), "-b" | "--bundle") => {
+                command.bundle = value.map(PathBuf::from)
+            }
+            (false, Some("create" | "run"), "-f" | "--config") => {
+                command.config = value.map(PathBuf::from)
+            }
+            (false, Some("exec"), "-p" | "--process") => command.process = value.map(PathBuf::from),
+            (false, Some("exec"), "-c" | "--cap") => command.caps.extend(value.map(String::from)),
+            (false, _, "--pid-file") => command.pid_file = value.map(PathBuf::from),
+            (false, _, "--console-socket") => command.console_socket = value.map(PathBuf::from),
+            (false, Some("run" | "exec"), "-d" | "--detach") => {
+                command.detach = value != Some("false")
+            }
+            _ => {}
+        }
+    }
+    command
+}
+
+/// Loads the config of an existing container, using the bundle reported by the runtime's `state`
+/// command, and any alternate config path recorded in the container's annotations when it was
+/// created.
+pub(crate) fn load_container_spec(
+    runtime_path: &str,
+    command: &RuntimeCommand,
+    cwd: &Path,
+) -> Result<Spec> {
+    let container_id = command
+        .container_id
+        .as_deref()
+        .with_context(|| "Unable to determine container ID")?;
+    let mut state_command = process::Command::new(runtime_path);
+    if let Some(root) = &command.root {
+        state_command.arg("--root").arg(root);
+    }
+    let output = state_command
+        .args(["state", container_id])
+        .output()
+        .with_context(|| "Failed to execute underlying OCI runtime")?;
+    if !output.status.success() {
+        bail!(
+            "Unable to query state of container {container_id}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let state: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .with_context(|| "Unable to parse container state")?;
+    let bundle = state["bundle"]
+        .as_str()
+        .with_context(|| "Container state does not include a bundle")?;
+    let config = state["annotations"][format!("{ANNOTATION_PREFIX}{CONFIG_ANNOTATION}")].as_str();
+    let container = RuntimeCommand {
+        subcommand: Some(String::from("create")),
+        bundle: Some(PathBuf::from(bundle)),
+        config: config.map(PathBuf::from),
+        ..RuntimeCommand::default()
+    };
+    let config_path = container
+        .config_path(cwd)
+        .with_context(|| "Unable to determine container's config path")?;
+    Spec::load(config_path).with_context(|| "Unable to parse container's OCI runtime specification")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> RuntimeCommand {
+        let options: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        parse_runtime_command(&options)
+    }
+
+    #[test]
+    fn parses_subcommand_and_container_id() {
+        assert_eq!(
+            parse(&[
+                "--root",
+                "/run/runc",
+                "--log=/tmp/log.json",
+                "--log-format",
+                "json",
+                "--systemd-cgroup",
+                "create",
+                "--bundle",
+                "/tmp/bundle",
+                "--pid-file",
+                "/tmp/pid",
+                "--console-socket=/tmp/console.sock",
+                "cid",
+            ]),
+            RuntimeCommand {
+                root: Some(PathBuf::from("/run/runc")),
+                log: Some(PathBuf::from("/tmp/log.json")),
+                log_format: Some(String::from("json")),
+                systemd_cgroup: true,
+                subcommand: Some(String::from("create")),
+                container_id: Some(String::from("cid")),
+                bundle: Some(PathBuf::from("/tmp/bundle")),
+                config: None,
+                process: None,
+                caps: vec![],
+                pid_file: Some(PathBuf::from("/tmp/pid")),
+                console_socket: Some(PathBuf::from("/tmp/console.sock")),
+                detach: false,
+            }
+        );
+        assert_eq!(
+            parse(&["delete", "--force", "cid"]).container_id.as_deref(),
+            Some("cid")
+        );
+        assert_eq!(parse(&["--version"]), RuntimeCommand::default());
+    }
+
+    #[test]
+    fn stops_at_the_container_id() {
+        let command = parse(&["exec", "-e", "A=1", "-t", "cid", "sh", "-b", "/tmp/bundle"]);
+        assert_eq!(command.container_id.as_deref(), Some("cid"));
+        assert_eq!(command.bundle, None);
+        let command = parse(&["exec", "--process", "process.json", "--detach", "cid"]);
+        assert_eq!(command.container_id.as_deref(), Some("cid"));
+        assert_eq!(
+            command.process_path(Path::new("/tmp")),
+            Some(PathBuf::from("/tmp/process.json"))
+        );
+        assert!(command.detach);
+        let command = parse(&["exec", "-c", "CAP_KILL", "--cap=CAP_CHOWN", "cid", "sh"]);
+        assert_eq!(command.caps, ["CAP_KILL", "CAP_CHOWN"]);
+        assert_eq!(
+            parse(&["kill", "cid", "SIGKILL"]).container_id.as_deref(),
+            Some("cid")
+        );
+        let command = parse(&["run", "-d", "--bundle", "/tmp/bundle", "--", "-cid"]);
+        assert_eq!(command.container_id.as_deref(), Some("-cid"));
+        assert!(command.detach);
+    }
+
+    #[test]
+    fn does_not_mistake_other_options_for_bundles() {
+        assert_eq!(parse(&["create", "-bogus", "cid"]).bundle, None);
+        assert_eq!(
+            parse(&["--bundle", "/tmp/bundle", "state", "cid"]).bundle,
+            None
+        );
+        assert_eq!(
+            parse(&["state", "--bundle=/tmp/bundle", "cid"]).bundle,
+            None
+        );
+        let command = parse(&["update", "--blkio-weight", "500", "cid"]);
+        assert_eq!(command.bundle, None);
+        assert_eq!(command.container_id.as_deref(), Some("cid"));
+    }
+
+    #[test]
+    fn rewrites_subcommand_options() {
+        let mut options: Vec<String> = ["--root", "/run/runc", "update", "--memory", "1g"]
+            .iter()
+            .chain(&["--pids-limit=200", "--memory-swap", "-1", "cid"])
+            .map(|s| s.to_string())
+            .collect();
+        let parsed = subcommand_options(&options);
+        let names: Vec<&str> = parsed.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, ["--memory", "--pids-limit", "--memory-swap"]);
+        parsed[0].set_value(&mut options, "512m");
+        parsed[1].set_value(&mut options, "100");
+        assert_eq!(
+            options,
+            [
+                "--root",
+                "/run/runc",
+                "update",
+                "--memory",
+                "512m",
+                "--pids-limit=100",
+                "--memory-swap",
+                "-1",
+                "cid"
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_bundle_paths() {
+        let cwd = Path::new("/run/containerd/io.containerd.runtime.v2.task/moby/cid");
+        let bundle_path = |args: &[&str]| parse(args).bundle_path(cwd);
+        assert_eq!(
+            bundle_path(&["create", "--bundle", "/tmp/bundle", "cid"]),
+            Some(PathBuf::from("/tmp/bundle"))
+        );
+        assert_eq!(
+            bundle_path(&["create", "--bundle", "bundle", "cid"]),
+            Some(cwd.join("bundle"))
+        );
+        assert_eq!(
+            bundle_path(&["run", "-b", "../other", "cid"]),
+            Some(cwd.join("../other"))
+        );
+        assert_eq!(bundle_path(&["create", "cid"]), Some(cwd.to_path_buf()));
+        assert_eq!(bundle_path(&["run", "-d", "cid"]), Some(cwd.to_path_buf()));
+        assert_eq!(bundle_path(&["restore", "cid"]), Some(cwd.to_path_buf()));
+        assert_eq!(
+            bundle_path(&["restore", "--bundle", "/tmp/bundle", "cid"]),
+            Some(PathBuf::from("/tmp/bundle"))
+        );
+        assert_eq!(bundle_path(&["start", "cid"]), None);
+        assert_eq!(bundle_path(&["--version"]), None);
+    }
+
+    #[test]
+    fn resolves_config_paths() {
+        let cwd = Path::new("/var/lib/containers");
+        let config_path = |args: &[&str]| parse(args).config_path(cwd);
+        assert_eq!(
+            config_path(&["create", "--bundle", "/tmp/bundle", "cid"]),
+            Some(PathBuf::from("/tmp/bundle/config.json"))
+        );
+        assert_eq!(
+            config_path(&[
+                "create",
+                "--bundle",
+                "/tmp/bundle",
+                "--config",
+                "alt.json",
+                "cid"
+            ]),
+            Some(PathBuf::from("/tmp/bundle/alt.json"))
+        );
+        assert_eq!(
+            config_path(&["run", "--config=/etc/alt.json", "cid"]),
+            Some(PathBuf::from("/etc/alt.json"))
+        );
+        assert_eq!(
+            config_path(&["run", "-f", "alt.json", "cid"]),
+            Some(cwd.join("alt.json"))
+        );
+        assert_eq!(config_path(&["list", "-f", "json"]), None);
+    }
+
+    #[test]
+    fn finds_bundle_short_flag_space_separated() {
+        let command = parse(&["create", "-b", "/tmp/bundle", "cid"]);
+        assert_eq!(command.bundle, Some(PathBuf::from("/tmp/bundle")));
+    }
+
+    #[test]
+    fn finds_bundle_short_flag_with_equals() {
+        let command = parse(&["create", "-b=/tmp/bundle", "cid"]);
+        assert_eq!(command.bundle, Some(PathBuf::from("/tmp/bundle")));
+    }
+
+    #[test]
+    fn finds_bundle_long_flag_space_separated() {
+        let command = parse(&["create", "--bundle", "/tmp/bundle", "cid"]);
+        assert_eq!(command.bundle, Some(PathBuf::from("/tmp/bundle")));
+    }
+
+    #[test]
+    fn finds_bundle_long_flag_with_equals() {
+        let command = parse(&["create", "--bundle=/tmp/bundle", "cid"]);
+        assert_eq!(command.bundle, Some(PathBuf::from("/tmp/bundle")));
+    }
+
+    #[test]
+    fn returns_none_when_no_bundle_flag_present() {
+        assert_eq!(parse(&["start", "cid"]).bundle, None);
+    }
+
+    #[test]
+    fn returns_none_for_empty_options() {
+        assert_eq!(parse(&[]), RuntimeCommand::default());
+    }
+
+    #[test]
+    fn returns_none_when_short_flag_has_no_following_arg() {
+        assert_eq!(parse(&["create", "-b"]).bundle, None);
+    }
+}
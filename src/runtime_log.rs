@@ -0,0 +1,113 @@
+use crate::runtime_command::RuntimeCommand;
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The log file passed to the runtime with `--log`, to which the interceptor also reports its own
+/// errors so that they are shown by the container engine.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RuntimeLog {
+    path: PathBuf,
+    /// Whether entries are written in runc's JSON format (`--log-format json`), rather than its
+    /// default text format.
+    json: bool,
+}
+
+impl RuntimeLog {
+    /// Returns the log file of a runtime invocation, if any.
+    pub(crate) fn new(command: &RuntimeCommand) -> Option<RuntimeLog> {
+        Some(RuntimeLog {
+            path: command.log.clone()?,
+            json: command.log_format.as_deref() == Some("json"),
+        })
+    }
+
+    /// Formats a log entry, e.g. `{"level":"error","msg":"...","time":"..."}`.
+    fn entry(&self, level: &str, msg: &str, time: SystemTime) -> String {
+        let time = format_time(time);
+        if self.json {
+            serde_json::json!({"level": level, "msg": msg, "time": time}).to_string()
+        } else {
+            format!("time=\"{time}\" level={level} msg={msg:?}")
+        }
+    }
+
+    /// Appends an error to the log.
+    pub(crate) fn write_error(&self, err: &anyhow::Error) -> Result<()> {
+        let msg = format!("oci-interceptor: {err:#}");
+        let mut file = fs::File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Unable to open runtime log {}", self.path.display()))?;
+        writeln!(file, "{}", self.entry("error", &msg, SystemTime::now()))?;
+        Ok(())
+    }
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with nanoseconds, as runc does.
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Convert days since the epoch to a civil date (see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(
+            format_time(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            "2023-11-14T22:13:20.123456789Z"
+        );
+        assert_eq!(
+            format_time(UNIX_EPOCH + Duration::from_secs(951_825_600)),
+            "2000-02-29T12:00:00.000000000Z"
+        );
+    }
+
+    #[test]
+    fn formats_entries() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let log = |json| RuntimeLog {
+            path: PathBuf::from("/run/log.json"),
+            json,
+        };
+        assert_eq!(
+            log(true).entry("error", "oci-interceptor: \"denied\"", time),
+            r#"{"level":"error","msg":"oci-interceptor: \"denied\"","time":"2023-11-14T22:13:20.000000000Z"}"#
+        );
+        assert_eq!(
+            log(false).entry("error", "oci-interceptor: denied", time),
+            r#"time="2023-11-14T22:13:20.000000000Z" level=error msg="oci-interceptor: denied""#
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use crate::state_file::with_state;
+use anyhow::Result;
+use std::{collections::BTreeMap, path::Path};
+
+/// Runtime paths chosen for containers, keyed by container ID.
+type Routes = BTreeMap<String, String>;
+
+/// Remembers the runtime chosen for a container, so that later calls for the same container are
+/// forwarded to it.
+///
+/// A runtime already recorded for the container ID is kept (e.g. for a live container with the
+/// same ID), and returned so that the call can be forwarded to it instead.
+pub(crate) fn record_runtime(
+    state_path: &Path,
+    container_id: &str,
+    runtime: &str,
+) -> Result<Option<String>> {
+    with_state(state_path, "runtime", |routes: &mut Routes| {
+        if let Some(existing) = routes.get(container_id) {
+            return Ok(Some(existing.clone()));
+        }
+        routes.insert(String::from(container_id), String::from(runtime));
+        Ok(None)
+    })
+}
+
+/// Returns the runtime chosen for a container when it was created, if any.
+pub(crate) fn recorded_runtime(state_path: &Path, container_id: &str) -> Result<Option<String>> {
+    with_state(state_path, "runtime", |routes: &mut Routes| {
+        Ok(routes.get(container_id).cloned())
+    })
+}
+
+/// Forgets the runtime chosen for a container which no longer exists.
+pub(crate) fn forget_runtime(state_path: &Path, container_id: &str) -> Result<()> {
+    with_state(state_path, "runtime", |routes: &mut Routes| {
+        routes.remove(container_id);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_runtimes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("runtimes.json");
+        assert_eq!(recorded_runtime(&state_path, "cid").unwrap(), None);
+        assert_eq!(
+            record_runtime(&state_path, "cid", "/usr/bin/runsc").unwrap(),
+            None
+        );
+        record_runtime(&state_path, "other", "runc").unwrap();
+        assert_eq!(
+            recorded_runtime(&state_path, "cid").unwrap().as_deref(),
+            Some("/usr/bin/runsc")
+        );
+
+        // Existing routes are not overwritten
+        assert_eq!(
+            record_runtime(&state_path, "cid", "runc")
+                .unwrap()
+                .as_deref(),
+            Some("/usr/bin/runsc")
+        );
+        assert_eq!(
+            recorded_runtime(&state_path, "cid").unwrap().as_deref(),
+            Some("/usr/bin/runsc")
+        );
+        forget_runtime(&state_path, "cid").unwrap();
+        assert_eq!(recorded_runtime(&state_path, "cid").unwrap(), None);
+        assert_eq!(
+            recorded_runtime(&state_path, "other").unwrap().as_deref(),
+            Some("runc")
+        );
+    }
+}
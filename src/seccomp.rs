@@ -0,0 +1,516 @@
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{
+    Arch, LinuxSeccomp, LinuxSeccompAction, LinuxSeccompArg, LinuxSeccompFilterFlag, LinuxSyscall,
+    Spec,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Error number returned for syscalls denied with `--oi-seccomp-deny` (EPERM).
+const DENY_ERRNO: u32 = 1;
+
+/// A seccomp profile in Docker's format.
+///
+/// Docker's format is a superset of the OCI `linux.seccomp` structure, adding an architecture map
+/// and conditional rules, so OCI-format profiles can be loaded as-is.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SeccompProfile {
+    default_action: LinuxSeccompAction,
+    #[serde(default)]
+    default_errno_ret: Option<u32>,
+    #[serde(default)]
+    architectures: Option<Vec<Arch>>,
+    #[serde(default)]
+    arch_map: Option<Vec<ArchMap>>,
+    #[serde(default)]
+    flags: Option<Vec<LinuxSeccompFilterFlag>>,
+    #[serde(default)]
+    listener_path: Option<PathBuf>,
+    #[serde(default)]
+    listener_metadata: Option<String>,
+    #[serde(default)]
+    syscalls: Vec<Syscall>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchMap {
+    architecture: Arch,
+    #[serde(default)]
+    sub_architectures: Vec<Arch>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Syscall {
+    /// Deprecated single-syscall form of `names`.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    names: Vec<String>,
+    action: LinuxSeccompAction,
+    #[serde(default)]
+    errno_ret: Option<u32>,
+    #[serde(default)]
+    args: Option<Vec<LinuxSeccompArg>>,
+    #[serde(default)]
+    includes: Filter,
+    #[serde(default)]
+    excludes: Filter,
+}
+
+/// Conditions under which a rule in a Docker-format profile applies.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Filter {
+    #[serde(default)]
+    arches: Vec<String>,
+    #[serde(default)]
+    caps: Vec<String>,
+    #[serde(default)]
+    min_kernel: Option<String>,
+}
+
+/// Properties of the host which determine the conditional rules of a Docker-format profile.
+#[derive(Clone, Debug)]
+struct Host {
+    /// Architecture name as used by Go (and therefore Docker profiles), e.g. `amd64`.
+    arch: &'static str,
+    /// Kernel version as (major, minor), if known.
+    kernel: Option<(u32, u32)>,
+}
+
+impl Host {
+    fn current() -> Self {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "powerpc64" => "ppc64",
+            "mips64" if cfg!(target_endian = "little") => "mips64le",
+            other => other,
+        };
+        let kernel = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .and_then(|release| parse_kernel_version(&release));
+        Self { arch, kernel }
+    }
+
+    /// Returns the seccomp architecture corresponding to the host architecture.
+    fn seccomp_arch(&self) -> Option<Arch> {
+        match self.arch {
+            "amd64" => Some(Arch::ScmpArchX86_64),
+            "386" => Some(Arch::ScmpArchX86),
+            "arm64" => Some(Arch::ScmpArchAarch64),
+            "arm" => Some(Arch::ScmpArchArm),
+            "ppc64" => Some(Arch::ScmpArchPpc64),
+            "ppc64le" => Some(Arch::ScmpArchPpc64le),
+            "s390x" => Some(Arch::ScmpArchS390x),
+            "mips64" => Some(Arch::ScmpArchMips64),
+            "mips64le" => Some(Arch::ScmpArchMipsel64),
+            "riscv64" => Some(Arch::ScmpArchRiscv64),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the major and minor version from a kernel release string such as `6.8.0-45-generic`.
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Loads a seccomp profile in either Docker or OCI format.
+pub(crate) fn load_seccomp_profile(path: &Path) -> Result<SeccompProfile> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read seccomp profile {}", path.display()))?;
+    let profile: SeccompProfile = serde_json::from_str(&contents)
+        .with_context(|| format!("Unable to parse seccomp profile {}", path.display()))?;
+    if profile.architectures.is_some() && profile.arch_map.is_some() {
+        bail!("Seccomp profile may specify architectures or archMap, but not both");
+    }
+    if let Some(syscall) = profile
+        .syscalls
+        .iter()
+        .find(|s| s.name.is_some() && !s.names.is_empty())
+    {
+        bail!(
+            "Seccomp rule for {} may specify name or names, but not both",
+            syscall.name.as_deref().unwrap_or_default()
+        );
+    }
+    Ok(profile)
+}
+
+impl SeccompProfile {
+    /// Converts the profile to the OCI structure for a container with the given capabilities.
+    fn to_oci(&self, capabilities: &[String], host: &Host) -> Result<LinuxSeccomp> {
+        let architectures = match &self.arch_map {
+            Some(arch_map) => Some(
+                arch_map
+                    .iter()
+                    .filter(|a| Some(a.architecture) == host.seccomp_arch())
+                    .flat_map(|a| {
+                        std::iter::once(a.architecture).chain(a.sub_architectures.iter().copied())
+                    })
+                    .collect(),
+            ),
+            None => self.architectures.clone(),
+        };
+
+        let mut syscalls = Vec::new();
+        for syscall in &self.syscalls {
+            if !syscall.applies(capabilities, host)? {
+                continue;
+            }
+            let names = match &syscall.name {
+                Some(name) => vec![name.clone()],
+                None => syscall.names.clone(),
+            };
+            let mut rule = LinuxSyscall::default();
+            rule.set_names(names);
+            rule.set_action(syscall.action);
+            rule.set_errno_ret(syscall.errno_ret);
+            rule.set_args(syscall.args.clone());
+            syscalls.push(rule);
+        }
+
+        let mut seccomp = LinuxSeccomp::default();
+        seccomp.set_default_action(self.default_action);
+        seccomp.set_default_errno_ret(self.default_errno_ret);
+        seccomp.set_architectures(architectures);
+        seccomp.set_flags(self.flags.clone());
+        seccomp.set_listener_path(self.listener_path.clone());
+        seccomp.set_listener_metadata(self.listener_metadata.clone());
+        seccomp.set_syscalls(Some(syscalls));
+        Ok(seccomp)
+    }
+}
+
+impl Syscall {
+    /// Evaluates the rule's includes and excludes, as Docker does when loading a profile.
+    fn applies(&self, capabilities: &[String], host: &Host) -> Result<bool> {
+        let includes = &self.includes;
+        if !includes.arches.is_empty() && !includes.arches.iter().any(|a| a == host.arch) {
+            return Ok(false);
+        }
+        if !includes.caps.iter().all(|c| capabilities.contains(c)) {
+            return Ok(false);
+        }
+        if let Some(min_kernel) = &includes.min_kernel {
+            let min_kernel = parse_kernel_version(min_kernel)
+                .with_context(|| format!("Invalid minKernel version \"{min_kernel}\""))?;
+            if host.kernel.is_some_and(|kernel| kernel < min_kernel) {
+                return Ok(false);
+            }
+        }
+        let excludes = &self.excludes;
+        if excludes.arches.iter().any(|a| a == host.arch) {
+            return Ok(false);
+        }
+        if excludes.caps.iter().any(|c| capabilities.contains(c)) {
+            return Ok(false);
+        }
+        if excludes.min_kernel.is_some() {
+            bail!("minKernel is not supported in seccomp rule excludes");
+        }
+        Ok(true)
+    }
+}
+
+/// Returns the names of the container's bounding capabilities, e.g. `CAP_SYS_ADMIN`.
+fn bounding_capabilities(spec: &Spec) -> Vec<String> {
+    spec.process()
+        .as_ref()
+        .and_then(|p| p.capabilities().as_ref())
+        .and_then(|c| c.bounding().as_ref())
+        .map(|caps| {
+            caps.iter()
+                .filter_map(|cap| serde_json::to_value(cap).ok())
+                .filter_map(|cap| cap.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Replaces the container's seccomp profile and/or adds rules denying the given syscalls.
+///
+/// Denied syscalls are removed from any existing rules, so that they cannot be allowed by the
+/// original profile. If the container has no seccomp profile (e.g. it was started with
+/// `--security-opt seccomp=unconfined`), a profile allowing all other syscalls is created.
+pub(crate) fn modify_seccomp(
+    spec: &mut Spec,
+    profile: Option<&SeccompProfile>,
+    deny: &[String],
+) -> Result<()> {
+    modify_seccomp_for_host(spec, profile, deny, &Host::current())
+}
+
+fn modify_seccomp_for_host(
+    spec: &mut Spec,
+    profile: Option<&SeccompProfile>,
+    deny: &[String],
+    host: &Host,
+) -> Result<()> {
+    let Some(linux) = spec.linux() else {
+        return Ok(());
+    };
+    let mut linux = linux.clone();
+    let mut seccomp = match profile {
+        Some(profile) => Some(profile.to_oci(&bounding_capabilities(spec), host)?),
+        None => linux.seccomp().clone(),
+    };
+
+    if !deny.is_empty() {
+        let mut new_seccomp = seccomp.unwrap_or_default();
+        let mut syscalls = new_seccomp.syscalls().clone().unwrap_or_default();
+        for syscall in syscalls.iter_mut() {
+            let mut names = syscall.names().clone();
+            names.retain(|name| !deny.contains(name));
+            syscall.set_names(names);
+        }
+        syscalls.retain(|syscall| !syscall.names().is_empty());
+        let mut rule = LinuxSyscall::default();
+        rule.set_names(deny.to_vec());
+        rule.set_action(LinuxSeccompAction::ScmpActErrno);
+        rule.set_errno_ret(Some(DENY_ERRNO));
+        syscalls.push(rule);
+        new_seccomp.set_syscalls(Some(syscalls));
+        seccomp = Some(new_seccomp);
+    }
+
+    linux.set_seccomp(seccomp);
+    spec.set_linux(Some(linux));
+    Ok(())
+}
+
+/// Makes syscalls which are allowed by an explicit seccomp rule or not matched by any rule be
+/// logged and allowed, rather than subject to the profile's default action. Rules with other
+/// actions (e.g. denying a syscall) are kept as-is.
+///
+/// The resulting audit records can be used to generate a profile with `oi-seccomp-generate`.
+pub(crate) fn modify_seccomp_log_mode(spec: &mut Spec) {
+    if let Some(linux) = spec.linux() {
+        let mut linux = linux.clone();
+        let mut seccomp = linux.seccomp().clone().unwrap_or_default();
+        seccomp.set_default_action(LinuxSeccompAction::ScmpActLog);
+        seccomp.set_default_errno_ret(None);
+        if let Some(syscalls) = seccomp.syscalls() {
+            let mut syscalls = syscalls.clone();
+            for syscall in syscalls
+                .iter_mut()
+                .filter(|s| s.action() == LinuxSeccompAction::ScmpActAllow)
+            {
+                syscall.set_action(LinuxSeccompAction::ScmpActLog);
+            }
+            seccomp.set_syscalls(Some(syscalls));
+        }
+        linux.set_seccomp(Some(seccomp));
+        spec.set_linux(Some(linux));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seccomp_audit::generate_seccomp_profile;
+
+    const AMD64: Host = Host {
+        arch: "amd64",
+        kernel: Some((5, 4)),
+    };
+
+    fn profile(json: &str) -> SeccompProfile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn seccomp(spec: &Spec) -> LinuxSeccomp {
+        spec.linux().as_ref().unwrap().seccomp().clone().unwrap()
+    }
+
+    fn rule_for(seccomp: &LinuxSeccomp, name: &str) -> Option<LinuxSyscall> {
+        seccomp
+            .syscalls()
+            .as_ref()?
+            .iter()
+            .find(|s| s.names().iter().any(|n| n == name))
+            .cloned()
+    }
+
+    #[test]
+    fn parses_kernel_versions() {
+        assert_eq!(parse_kernel_version("6.8.0-45-generic\n"), Some((6, 8)));
+        assert_eq!(parse_kernel_version("4.14"), Some((4, 14)));
+        assert_eq!(parse_kernel_version("5"), None);
+    }
+
+    #[test]
+    fn converts_docker_profile() {
+        let profile = profile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "defaultErrnoRet": 1,
+                "archMap": [
+                    {
+                        "architecture": "SCMP_ARCH_X86_64",
+                        "subArchitectures": ["SCMP_ARCH_X86", "SCMP_ARCH_X32"]
+                    },
+                    {
+                        "architecture": "SCMP_ARCH_AARCH64",
+                        "subArchitectures": ["SCMP_ARCH_ARM"]
+                    }
+                ],
+                "syscalls": [
+                    {"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"},
+                    {"name": "arch_prctl", "action": "SCMP_ACT_ALLOW",
+                     "includes": {"arches": ["amd64"]}},
+                    {"names": ["arm_fadvise64_64"], "action": "SCMP_ACT_ALLOW",
+                     "includes": {"arches": ["arm", "arm64"]}},
+                    {"names": ["mount"], "action": "SCMP_ACT_ALLOW",
+                     "includes": {"caps": ["CAP_SYS_ADMIN"]}},
+                    {"names": ["clone"], "action": "SCMP_ACT_ALLOW",
+                     "excludes": {"caps": ["CAP_SYS_ADMIN"]}},
+                    {"names": ["landlock_create_ruleset"], "action": "SCMP_ACT_ALLOW",
+                     "includes": {"minKernel": "5.13"}}
+                ]
+            }"#,
+        );
+        let seccomp = profile.to_oci(&[String::from("CAP_KILL")], &AMD64).unwrap();
+        assert_eq!(seccomp.default_action(), LinuxSeccompAction::ScmpActErrno);
+        assert_eq!(seccomp.default_errno_ret(), Some(1));
+        assert_eq!(
+            seccomp.architectures().as_deref(),
+            Some(&[Arch::ScmpArchX86_64, Arch::ScmpArchX86, Arch::ScmpArchX32][..])
+        );
+        assert!(rule_for(&seccomp, "read").is_some());
+        assert!(rule_for(&seccomp, "arch_prctl").is_some());
+        assert!(rule_for(&seccomp, "arm_fadvise64_64").is_none());
+        assert!(rule_for(&seccomp, "mount").is_none());
+        assert!(rule_for(&seccomp, "clone").is_some());
+        assert!(rule_for(&seccomp, "landlock_create_ruleset").is_none());
+
+        let seccomp = profile
+            .to_oci(&[String::from("CAP_SYS_ADMIN")], &AMD64)
+            .unwrap();
+        assert!(rule_for(&seccomp, "mount").is_some());
+        assert!(rule_for(&seccomp, "clone").is_none());
+    }
+
+    #[test]
+    fn converts_oci_profile() {
+        let profile = profile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ALLOW",
+                "architectures": ["SCMP_ARCH_X86_64"],
+                "syscalls": [
+                    {"names": ["ptrace"], "action": "SCMP_ACT_ERRNO", "errnoRet": 38}
+                ]
+            }"#,
+        );
+        let seccomp = profile.to_oci(&[], &AMD64).unwrap();
+        assert_eq!(
+            seccomp.architectures().as_deref(),
+            Some(&[Arch::ScmpArchX86_64][..])
+        );
+        let rule = rule_for(&seccomp, "ptrace").unwrap();
+        assert_eq!(rule.action(), LinuxSeccompAction::ScmpActErrno);
+        assert_eq!(rule.errno_ret(), Some(38));
+    }
+
+    #[test]
+    fn replaces_container_profile() {
+        let profile = profile(r#"{"defaultAction": "SCMP_ACT_KILL_PROCESS"}"#);
+        let mut spec = Spec::default();
+        modify_seccomp_for_host(&mut spec, Some(&profile), &[], &AMD64).unwrap();
+        assert_eq!(
+            seccomp(&spec).default_action(),
+            LinuxSeccompAction::ScmpActKillProcess
+        );
+    }
+
+    #[test]
+    fn merges_deny_rules_into_existing_profile() {
+        let mut spec = Spec::default();
+        let existing = profile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "syscalls": [
+                    {"names": ["read", "keyctl"], "action": "SCMP_ACT_ALLOW"},
+                    {"names": ["userfaultfd"], "action": "SCMP_ACT_ALLOW"}
+                ]
+            }"#,
+        );
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_seccomp(Some(existing.to_oci(&[], &AMD64).unwrap()));
+        spec.set_linux(Some(linux));
+
+        let deny = [String::from("userfaultfd"), String::from("keyctl")];
+        modify_seccomp_for_host(&mut spec, None, &deny, &AMD64).unwrap();
+        let seccomp = seccomp(&spec);
+        assert_eq!(seccomp.default_action(), LinuxSeccompAction::ScmpActErrno);
+        let syscalls = seccomp.syscalls().clone().unwrap();
+        assert_eq!(syscalls.len(), 2);
+        assert_eq!(syscalls[0].names(), &[String::from("read")]);
+        assert_eq!(syscalls[1].names(), &deny);
+        assert_eq!(syscalls[1].action(), LinuxSeccompAction::ScmpActErrno);
+        assert_eq!(syscalls[1].errno_ret(), Some(DENY_ERRNO));
+    }
+
+    #[test]
+    fn log_mode_keeps_deny_rules_and_logs_allowed_syscalls() {
+        let mut spec = Spec::default();
+        let existing = profile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "defaultErrnoRet": 1,
+                "syscalls": [
+                    {"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"},
+                    {"names": ["keyctl"], "action": "SCMP_ACT_ERRNO"}
+                ]
+            }"#,
+        );
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_seccomp(Some(existing.to_oci(&[], &AMD64).unwrap()));
+        spec.set_linux(Some(linux));
+
+        modify_seccomp_log_mode(&mut spec);
+        let seccomp = seccomp(&spec);
+        assert_eq!(seccomp.default_action(), LinuxSeccompAction::ScmpActLog);
+        assert_eq!(seccomp.default_errno_ret(), None);
+        assert_eq!(
+            rule_for(&seccomp, "keyctl").unwrap().action(),
+            LinuxSeccompAction::ScmpActErrno
+        );
+        assert_eq!(
+            rule_for(&seccomp, "read").unwrap().action(),
+            LinuxSeccompAction::ScmpActLog
+        );
+
+        // Explicitly allowed syscalls are audited, so they are allowed by the generated profile
+        let audit_log = "type=SECCOMP msg=audit(11/14/2023 22:13:20.125:458) : pid=4244 \
+            arch=x86_64 syscall=read compat=0 ip=0x7f13 code=log";
+        let generated = generate_seccomp_profile(audit_log.as_bytes(), &[4244]).unwrap();
+        assert_eq!(
+            rule_for(&generated, "read").unwrap().action(),
+            LinuxSeccompAction::ScmpActAllow
+        );
+    }
+
+    #[test]
+    fn deny_rules_applied_to_unconfined_containers() {
+        let mut spec = Spec::default();
+        assert!(spec.linux().as_ref().unwrap().seccomp().is_none());
+        let deny = [String::from("keyctl")];
+        modify_seccomp_for_host(&mut spec, None, &deny, &AMD64).unwrap();
+        let seccomp = seccomp(&spec);
+        assert_eq!(seccomp.default_action(), LinuxSeccompAction::ScmpActAllow);
+        assert!(rule_for(&seccomp, "keyctl").is_some());
+    }
+}
@@ -0,0 +1,225 @@
+mod syscall_table;
+
+use anyhow::{Result, bail};
+use oci_spec::runtime::{Arch, LinuxSeccomp, LinuxSeccompAction, LinuxSyscall};
+use std::{collections::BTreeSet, io::BufRead};
+
+/// Error number returned for syscalls not in a generated profile (EPERM).
+const DEFAULT_ERRNO: u32 = 1;
+
+/// The `code` recorded in audit records for syscalls allowed by `SCMP_ACT_LOG`.
+const SECCOMP_RET_LOG: u32 = 0x7ffc0000;
+
+/// A seccomp audit record (`type=SECCOMP`, or `type=1326` in kernel log form).
+#[derive(Clone, Debug, PartialEq)]
+struct AuditRecord {
+    pid: Option<u32>,
+    arch: Option<Arch>,
+    /// Syscall name, or number if the record has not been interpreted (e.g. by `ausearch -i`).
+    syscall: String,
+    /// Whether the syscall was allowed by `SCMP_ACT_LOG`, if known.
+    logged: Option<bool>,
+}
+
+/// Parses a seccomp audit record from a line of audit log output, returning `None` for other
+/// records.
+fn parse_audit_line(line: &str) -> Option<AuditRecord> {
+    if !line.contains("type=SECCOMP") && !line.contains("type=1326") {
+        return None;
+    }
+    let mut pid = None;
+    let mut arch = None;
+    let mut syscall = None;
+    let mut logged = None;
+    for (key, value) in line
+        .split_whitespace()
+        .filter_map(|field| field.split_once('='))
+    {
+        match key {
+            "pid" => pid = value.parse().ok(),
+            "arch" => arch = parse_arch(value),
+            "syscall" => syscall = Some(String::from(value)),
+            "code" => {
+                logged = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16)
+                        .ok()
+                        .map(|code| code == SECCOMP_RET_LOG),
+                    None => Some(value == "log"),
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(AuditRecord {
+        pid,
+        arch,
+        syscall: syscall?,
+        logged,
+    })
+}
+
+/// Parses an audit architecture, either as a raw `AUDIT_ARCH_*` value or as interpreted by
+/// `ausearch -i`.
+fn parse_arch(value: &str) -> Option<Arch> {
+    match value {
+        "c000003e" | "x86_64" => Some(Arch::ScmpArchX86_64),
+        "40000003" | "i386" => Some(Arch::ScmpArchX86),
+        "c00000b7" | "aarch64" => Some(Arch::ScmpArchAarch64),
+        _ => None,
+    }
+}
+
+/// Resolves a syscall number from an uninterpreted audit record to its name.
+fn resolve_syscall(arch: Option<Arch>, syscall: &str) -> Result<String> {
+    let Ok(number) = syscall.parse::<u32>() else {
+        return Ok(String::from(syscall));
+    };
+    let table = match arch {
+        Some(Arch::ScmpArchX86_64) => syscall_table::X86_64,
+        Some(Arch::ScmpArchX86) => syscall_table::X86,
+        _ => bail!(
+            "Unable to resolve syscall number {number} for this architecture; use interpreted \
+            audit logs (ausearch -i) instead"
+        ),
+    };
+    match table.iter().find(|(n, _)| *n == number) {
+        Some((_, name)) => Ok(String::from(*name)),
+        None => bail!("Unknown syscall number {number}"),
+    }
+}
+
+/// Generates a minimal allow-list seccomp profile from the audit records of syscalls allowed by
+/// `SCMP_ACT_LOG`.
+///
+/// If `pids` is non-empty, only records for those processes are considered.
+pub(crate) fn generate_seccomp_profile(
+    audit_log: impl BufRead,
+    pids: &[u32],
+) -> Result<LinuxSeccomp> {
+    let mut architectures = Vec::new();
+    let mut names = BTreeSet::new();
+    for line in audit_log.lines() {
+        let Some(record) = parse_audit_line(&line?) else {
+            continue;
+        };
+        if !pids.is_empty() && !record.pid.is_some_and(|pid| pids.contains(&pid)) {
+            continue;
+        }
+        if record.logged == Some(false) {
+            continue;
+        }
+        if let Some(arch) = record.arch
+            && !architectures.contains(&arch)
+        {
+            architectures.push(arch);
+        }
+        names.insert(resolve_syscall(record.arch, &record.syscall)?);
+    }
+    if names.is_empty() {
+        bail!("No matching seccomp audit records found");
+    }
+
+    let mut rule = LinuxSyscall::default();
+    rule.set_names(names.into_iter().collect());
+    rule.set_action(LinuxSeccompAction::ScmpActAllow);
+    let mut seccomp = LinuxSeccomp::default();
+    seccomp.set_default_action(LinuxSeccompAction::ScmpActErrno);
+    seccomp.set_default_errno_ret(Some(DEFAULT_ERRNO));
+    if !architectures.is_empty() {
+        seccomp.set_architectures(Some(architectures));
+    }
+    seccomp.set_syscalls(Some(vec![rule]));
+    Ok(seccomp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "type=SECCOMP msg=audit(1700000000.123:456): auid=4294967295 uid=0 gid=0 \
+        ses=4294967295 subj=unconfined pid=4242 comm=\"sh\" exe=\"/bin/busybox\" sig=0 \
+        arch=c000003e syscall=59 compat=0 ip=0x7f12 code=0x7ffc0000";
+    const KERNEL: &str = "[ 12.345678] audit: type=1326 audit(1700000000.124:457): auid=4294967295 \
+        uid=0 gid=0 ses=4294967295 pid=4243 comm=\"chal\" exe=\"/chal\" sig=0 arch=40000003 \
+        syscall=3 compat=1 ip=0xf7f1 code=0x7ffc0000";
+    const INTERPRETED: &str = "type=SECCOMP msg=audit(11/14/2023 22:13:20.125:458) : auid=unset \
+        uid=root gid=root ses=unset pid=4244 comm=sh exe=/bin/busybox sig=SIG0 arch=x86_64 \
+        syscall=openat compat=0 ip=0x7f13 code=log";
+
+    #[test]
+    fn parses_raw_records() {
+        assert_eq!(
+            parse_audit_line(RAW),
+            Some(AuditRecord {
+                pid: Some(4242),
+                arch: Some(Arch::ScmpArchX86_64),
+                syscall: String::from("59"),
+                logged: Some(true),
+            })
+        );
+        assert_eq!(
+            parse_audit_line(KERNEL).unwrap().arch,
+            Some(Arch::ScmpArchX86)
+        );
+    }
+
+    #[test]
+    fn parses_interpreted_records() {
+        let record = parse_audit_line(INTERPRETED).unwrap();
+        assert_eq!(record.pid, Some(4244));
+        assert_eq!(record.arch, Some(Arch::ScmpArchX86_64));
+        assert_eq!(record.syscall, "openat");
+        assert_eq!(record.logged, Some(true));
+    }
+
+    #[test]
+    fn ignores_other_records() {
+        assert_eq!(
+            parse_audit_line("type=SYSCALL msg=audit(1.0:1): arch=c000003e syscall=59"),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_syscall_numbers() {
+        assert_eq!(
+            resolve_syscall(Some(Arch::ScmpArchX86_64), "59").unwrap(),
+            "execve"
+        );
+        assert_eq!(
+            resolve_syscall(Some(Arch::ScmpArchX86), "11").unwrap(),
+            "execve"
+        );
+        assert_eq!(resolve_syscall(None, "openat").unwrap(), "openat");
+        assert!(resolve_syscall(Some(Arch::ScmpArchAarch64), "221").is_err());
+        assert!(resolve_syscall(Some(Arch::ScmpArchX86_64), "9999").is_err());
+    }
+
+    #[test]
+    fn generates_profile_for_pids() {
+        let log = [RAW, KERNEL, INTERPRETED].join("\n");
+        let seccomp = generate_seccomp_profile(log.as_bytes(), &[4242, 4243]).unwrap();
+        assert_eq!(seccomp.default_action(), LinuxSeccompAction::ScmpActErrno);
+        assert_eq!(
+            seccomp.architectures().as_deref(),
+            Some(&[Arch::ScmpArchX86_64, Arch::ScmpArchX86][..])
+        );
+        let syscalls = seccomp.syscalls().clone().unwrap();
+        assert_eq!(syscalls.len(), 1);
+        assert_eq!(syscalls[0].names(), &["execve", "read"]);
+        assert_eq!(syscalls[0].action(), LinuxSeccompAction::ScmpActAllow);
+    }
+
+    #[test]
+    fn skips_records_not_allowed_by_log_action() {
+        let denied = RAW.replace("code=0x7ffc0000", "code=0x50001");
+        let log = [denied.as_str(), INTERPRETED].join("\n");
+        let seccomp = generate_seccomp_profile(log.as_bytes(), &[]).unwrap();
+        assert_eq!(seccomp.syscalls().clone().unwrap()[0].names(), &["openat"]);
+    }
+
+    #[test]
+    fn no_matching_records_is_an_error() {
+        assert!(generate_seccomp_profile(RAW.as_bytes(), &[1]).is_err());
+    }
+}
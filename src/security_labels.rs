@@ -0,0 +1,191 @@
+use crate::selector::{Selected, select};
+use anyhow::{Result, bail};
+use oci_spec::runtime::{Process, Spec};
+
+/// SELinux types which do not confine the container.
+const UNCONFINED_SELINUX_TYPES: &[&str] = &["unconfined_t", "spc_t"];
+
+/// AppArmor and SELinux labels to assign to containers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SecurityLabelPolicy {
+    pub(crate) apparmor_profiles: Vec<Selected<String>>,
+    pub(crate) selinux_labels: Vec<Selected<String>>,
+    pub(crate) selinux_mount_labels: Vec<Selected<String>>,
+    /// Reject containers which request to run without AppArmor or SELinux confinement.
+    pub(crate) reject_unconfined: bool,
+}
+
+impl SecurityLabelPolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.apparmor_profiles.is_empty()
+            && self.selinux_labels.is_empty()
+            && self.selinux_mount_labels.is_empty()
+            && !self.reject_unconfined
+    }
+}
+
+/// Returns whether an SELinux label (`user:role:type:level`) has an unconfined type.
+fn is_unconfined_selinux_label(label: &str) -> bool {
+    label
+        .split(':')
+        .nth(2)
+        .is_some_and(|typ| UNCONFINED_SELINUX_TYPES.contains(&typ))
+}
+
+/// Sets the AppArmor profile and SELinux label of a process of the container (e.g. its init
+/// process, or a process executed in it), replacing any labels it requested.
+///
+/// If unconfined processes are rejected, `subject` describes the process in the error.
+///
+/// Returns a description of each label which was set.
+pub(crate) fn modify_process_labels(
+    process: &mut Process,
+    container: &Spec,
+    policy: &SecurityLabelPolicy,
+    subject: &str,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    if policy.reject_unconfined {
+        if process.apparmor_profile().as_deref() == Some("unconfined") {
+            bail!("{subject} requested to run without an AppArmor profile");
+        }
+        if process
+            .selinux_label()
+            .as_deref()
+            .is_some_and(is_unconfined_selinux_label)
+        {
+            bail!("{subject} requested to run with an unconfined SELinux label");
+        }
+    }
+    if let Some(profile) = select(&policy.apparmor_profiles, container) {
+        changes.push(format!("AppArmor profile: {profile}"));
+        process.set_apparmor_profile(Some(profile.clone()));
+    }
+    if let Some(label) = select(&policy.selinux_labels, container) {
+        changes.push(format!("SELinux label: {label}"));
+        process.set_selinux_label(Some(label.clone()));
+    }
+    Ok(changes)
+}
+
+/// Sets the AppArmor profile, SELinux process label and SELinux mount label of the container,
+/// replacing any labels it requested.
+///
+/// Returns a description of each label which was set.
+pub(crate) fn modify_security_labels(
+    spec: &mut Spec,
+    policy: &SecurityLabelPolicy,
+) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    if let Some(process) = spec.process() {
+        let mut process = process.clone();
+        changes.extend(modify_process_labels(
+            &mut process,
+            spec,
+            policy,
+            "Container",
+        )?);
+        spec.set_process(Some(process));
+    }
+
+    if let Some(label) = select(&policy.selinux_mount_labels, spec).cloned()
+        && let Some(linux) = spec.linux()
+    {
+        let mut linux = linux.clone();
+        changes.push(format!("SELinux mount label: {label}"));
+        linux.set_mount_label(Some(label));
+        spec.set_linux(Some(linux));
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::parse_selected;
+
+    fn selected(values: &[&str]) -> Vec<Selected<String>> {
+        values.iter().map(|v| parse_selected(v).unwrap()).collect()
+    }
+
+    fn container(hostname: &str, apparmor_profile: &str) -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from(hostname)));
+        let mut process = spec.process().clone().unwrap();
+        process.set_apparmor_profile(Some(String::from(apparmor_profile)));
+        spec.set_process(Some(process));
+        spec
+    }
+
+    #[test]
+    fn assigns_labels_by_selector() {
+        let policy = SecurityLabelPolicy {
+            apparmor_profiles: selected(&["docker-default", "strict@hostname=chal-*"]),
+            selinux_labels: selected(&["system_u:system_r:container_t:s0:c1,c2"]),
+            selinux_mount_labels: selected(&["system_u:object_r:container_file_t:s0:c1,c2"]),
+            reject_unconfined: false,
+        };
+        let mut spec = container("chal-1", "docker-default");
+        let changes = modify_security_labels(&mut spec, &policy).unwrap();
+        let process = spec.process().as_ref().unwrap();
+        assert_eq!(process.apparmor_profile().as_deref(), Some("strict"));
+        assert_eq!(
+            process.selinux_label().as_deref(),
+            Some("system_u:system_r:container_t:s0:c1,c2")
+        );
+        assert_eq!(
+            spec.linux().as_ref().unwrap().mount_label().as_deref(),
+            Some("system_u:object_r:container_file_t:s0:c1,c2")
+        );
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0], "AppArmor profile: strict");
+
+        let mut spec = container("web", "docker-default");
+        modify_security_labels(&mut spec, &policy).unwrap();
+        assert_eq!(
+            spec.process()
+                .as_ref()
+                .unwrap()
+                .apparmor_profile()
+                .as_deref(),
+            Some("docker-default")
+        );
+    }
+
+    #[test]
+    fn overrides_unconfined_by_default() {
+        let policy = SecurityLabelPolicy {
+            apparmor_profiles: selected(&["strict"]),
+            ..Default::default()
+        };
+        let mut spec = container("chal-1", "unconfined");
+        modify_security_labels(&mut spec, &policy).unwrap();
+        assert_eq!(
+            spec.process()
+                .as_ref()
+                .unwrap()
+                .apparmor_profile()
+                .as_deref(),
+            Some("strict")
+        );
+    }
+
+    #[test]
+    fn rejects_unconfined() {
+        let policy = SecurityLabelPolicy {
+            apparmor_profiles: selected(&["strict"]),
+            reject_unconfined: true,
+            ..Default::default()
+        };
+        assert!(modify_security_labels(&mut container("chal-1", "unconfined"), &policy).is_err());
+        assert!(
+            modify_security_labels(&mut container("chal-1", "docker-default"), &policy).is_ok()
+        );
+
+        let mut spec = container("chal-1", "docker-default");
+        let mut process = spec.process().clone().unwrap();
+        process.set_selinux_label(Some(String::from("system_u:system_r:spc_t:s0")));
+        spec.set_process(Some(process));
+        assert!(modify_security_labels(&mut spec, &policy).is_err());
+    }
+}
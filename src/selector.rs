@@ -0,0 +1,177 @@
+use crate::glob::glob_match;
+use anyhow::{Context, bail};
+use oci_spec::runtime::Spec;
+
+/// Selects containers based on properties of their config.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Selector {
+    /// Matches containers whose hostname matches a pattern.
+    Hostname(String),
+    /// Matches containers with an annotation whose value matches a pattern.
+    Annotation { key: String, pattern: String },
+}
+
+impl Selector {
+    pub(crate) fn matches(&self, spec: &Spec) -> bool {
+        match self {
+            Selector::Hostname(pattern) => spec
+                .hostname()
+                .as_ref()
+                .is_some_and(|hostname| glob_match(pattern, hostname)),
+            Selector::Annotation { key, pattern } => spec
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(key))
+                .is_some_and(|value| glob_match(pattern, value)),
+        }
+    }
+}
+
+/// Parses a selector in `hostname=PATTERN` or `annotation:KEY=PATTERN` format.
+pub(crate) fn parse_selector(value: &str) -> Result<Selector, anyhow::Error> {
+    let err_msg = "selectors must be in hostname=PATTERN or annotation:KEY=PATTERN format";
+    let (lhs, pattern) = value.split_once('=').context(err_msg)?;
+    let selector = if lhs == "hostname" {
+        Selector::Hostname(String::from(pattern))
+    } else if let Some(key) = lhs.strip_prefix("annotation:")
+        && !key.is_empty()
+    {
+        Selector::Annotation {
+            key: String::from(key),
+            pattern: String::from(pattern),
+        }
+    } else {
+        bail!(err_msg);
+    };
+    Ok(selector)
+}
+
+/// A value which applies either to all containers, or only to those matching a selector.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Selected<T> {
+    pub(crate) value: T,
+    pub(crate) selector: Option<Selector>,
+}
+
+/// Parses a string value in `VALUE` or `VALUE@SELECTOR` format.
+pub(crate) fn parse_selected(value: &str) -> Result<Selected<String>, anyhow::Error> {
+    let (value, selector) = match value.split_once('@') {
+        Some((value, selector)) => (value, Some(parse_selector(selector)?)),
+        None => (value, None),
+    };
+    if value.is_empty() {
+        bail!("value must not be empty");
+    }
+    Ok(Selected {
+        value: String::from(value),
+        selector,
+    })
+}
+
+/// Returns the value which applies to a container.
+///
+/// The first value whose selector matches the container takes precedence. Otherwise, the first
+/// value without a selector is used, if any.
+pub(crate) fn select<'a, T>(values: &'a [Selected<T>], spec: &Spec) -> Option<&'a T> {
+    values
+        .iter()
+        .find(|v| v.selector.as_ref().is_some_and(|s| s.matches(spec)))
+        .or_else(|| values.iter().find(|v| v.selector.is_none()))
+        .map(|v| &v.value)
+}
+
+/// Returns all values which apply to a container: those whose selector matches the container,
+/// and those without a selector.
+pub(crate) fn select_all<'a, T>(
+    values: &'a [Selected<T>],
+    spec: &'a Spec,
+) -> impl Iterator<Item = &'a T> {
+    values
+        .iter()
+        .filter(|v| v.selector.as_ref().is_none_or(|s| s.matches(spec)))
+        .map(|v| &v.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn spec() -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from("chal-web-1")));
+        spec.set_annotations(Some(HashMap::from([(
+            String::from("org.example.tier"),
+            String::from("untrusted"),
+        )])));
+        spec
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert_eq!(
+            parse_selector("hostname=chal-*").unwrap(),
+            Selector::Hostname(String::from("chal-*"))
+        );
+        assert_eq!(
+            parse_selector("annotation:org.example.tier=untrusted").unwrap(),
+            Selector::Annotation {
+                key: String::from("org.example.tier"),
+                pattern: String::from("untrusted"),
+            }
+        );
+        assert!(parse_selector("image=alpine").is_err());
+        assert!(parse_selector("annotation:=x").is_err());
+        assert!(parse_selector("hostname").is_err());
+    }
+
+    #[test]
+    fn matches_containers() {
+        let spec = spec();
+        assert!(parse_selector("hostname=chal-*").unwrap().matches(&spec));
+        assert!(!parse_selector("hostname=judge-*").unwrap().matches(&spec));
+        assert!(
+            parse_selector("annotation:org.example.tier=untrusted")
+                .unwrap()
+                .matches(&spec)
+        );
+        assert!(
+            !parse_selector("annotation:org.example.missing=*")
+                .unwrap()
+                .matches(&spec)
+        );
+    }
+
+    #[test]
+    fn parses_selected_values() {
+        let v = parse_selected("strict@hostname=chal-*").unwrap();
+        assert_eq!(v.value, "strict");
+        assert_eq!(v.selector, Some(Selector::Hostname(String::from("chal-*"))));
+        let v = parse_selected("default").unwrap();
+        assert_eq!(v.value, "default");
+        assert_eq!(v.selector, None);
+        assert!(parse_selected("@hostname=chal-*").is_err());
+        assert!(parse_selected("strict@bogus").is_err());
+    }
+
+    #[test]
+    fn selected_values_take_precedence() {
+        let values: Vec<Selected<String>> = [
+            "default",
+            "judge@hostname=judge-*",
+            "strict@hostname=chal-*",
+        ]
+        .iter()
+        .map(|v| parse_selected(v).unwrap())
+        .collect();
+        assert_eq!(select(&values, &spec()).map(String::as_str), Some("strict"));
+        let mut other = spec();
+        other.set_hostname(Some(String::from("web")));
+        assert_eq!(select(&values, &other).map(String::as_str), Some("default"));
+        assert_eq!(select(&values[1..], &other), None);
+        assert_eq!(
+            select_all(&values, &spec()).collect::<Vec<&String>>(),
+            ["default", "strict"]
+        );
+    }
+}
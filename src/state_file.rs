@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    fs,
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+/// Runs a function on the state stored in a JSON state file, while holding an exclusive lock on
+/// it, and saves any changes.
+///
+/// Missing or empty state files hold the default state. `description` names the state in error
+/// messages (e.g. `cpuset`).
+pub(crate) fn with_state<S, T>(
+    state_path: &Path,
+    description: &str,
+    f: impl FnOnce(&mut S) -> Result<T>,
+) -> Result<T>
+where
+    S: Default + Serialize + DeserializeOwned,
+{
+    if let Some(parent) = state_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(state_path)
+        .with_context(|| {
+            format!(
+                "Unable to open {description} state file {}",
+                state_path.display()
+            )
+        })?;
+    file.lock()
+        .with_context(|| format!("Unable to lock {description} state file"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut state: S = if contents.trim().is_empty() {
+        S::default()
+    } else {
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse {description} state file"))?
+    };
+    let result = f(&mut state)?;
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(serde_json::to_string_pretty(&state)?.as_bytes())?;
+    Ok(result)
+}
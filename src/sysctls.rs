@@ -0,0 +1,236 @@
+use crate::glob::glob_match;
+use anyhow::{Context, bail};
+use oci_spec::runtime::{LinuxNamespaceType, Spec};
+
+/// Sysctls which are namespaced by the IPC namespace, in addition to those prefixed by
+/// `fs.mqueue.`. This matches the list accepted by runc.
+const IPC_SYSCTLS: &[&str] = &[
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+    "kernel.shm_rmid_forced",
+];
+
+#[derive(Clone, Debug)]
+pub(crate) struct Sysctl {
+    name: String,
+    value: String,
+}
+
+impl Sysctl {
+    pub(crate) fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: String::from(name),
+            value: String::from(value),
+        }
+    }
+}
+
+pub(crate) fn parse_sysctl(value: &str) -> Result<Sysctl, anyhow::Error> {
+    let err_msg = "sysctls must be in NAME=VALUE format";
+    let (name, value) = value.split_once('=').context(err_msg)?;
+    if name.is_empty() || value.is_empty() {
+        return Err(anyhow::anyhow!(err_msg));
+    }
+    if required_namespace(name).is_none() {
+        bail!("sysctl {name} is not namespaced and cannot be set for a container");
+    }
+    Ok(Sysctl::new(name, value))
+}
+
+/// Returns the namespace which a container must have in order to set the given sysctl, or `None`
+/// if the sysctl is not namespaced.
+fn required_namespace(name: &str) -> Option<LinuxNamespaceType> {
+    if IPC_SYSCTLS.contains(&name) || name.starts_with("fs.mqueue.") {
+        Some(LinuxNamespaceType::Ipc)
+    } else if name.starts_with("net.") {
+        Some(LinuxNamespaceType::Network)
+    } else if name == "kernel.hostname" || name == "kernel.domainname" {
+        Some(LinuxNamespaceType::Uts)
+    } else {
+        None
+    }
+}
+
+/// Restrictions on the sysctls which containers may request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SysctlPolicy {
+    /// If non-empty, only sysctls matching one of these patterns are permitted.
+    pub(crate) allow: Vec<String>,
+    /// Sysctls matching any of these patterns are never permitted.
+    pub(crate) deny: Vec<String>,
+    /// Reject containers requesting non-permitted sysctls, rather than removing them.
+    pub(crate) reject: bool,
+}
+
+impl SysctlPolicy {
+    fn permits(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, name)))
+            && !self.deny.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// Applies the sysctl policy to sysctls requested in the container config, then sets the given
+/// sysctls, overriding any original values.
+///
+/// Sysctls are only set if the container has the namespace they belong to (e.g. `net.*` sysctls
+/// are skipped for containers using the host network), as the runtime would otherwise refuse to
+/// create the container.
+pub(crate) fn modify_sysctls(
+    spec: &mut Spec,
+    sysctls: &[Sysctl],
+    policy: &SysctlPolicy,
+) -> Result<(), anyhow::Error> {
+    if let Some(linux) = spec.linux() {
+        let mut linux = linux.clone();
+        let mut new_sysctls = linux.sysctl().clone().unwrap_or_default();
+        let mut denied: Vec<String> = new_sysctls
+            .keys()
+            .filter(|name| !policy.permits(name))
+            .cloned()
+            .collect();
+        denied.sort();
+        if policy.reject && !denied.is_empty() {
+            bail!(
+                "Container requested forbidden sysctls: {}",
+                denied.join(", ")
+            );
+        }
+        for name in denied {
+            new_sysctls.remove(&name);
+        }
+
+        let namespaces = linux.namespaces().clone().unwrap_or_default();
+        for sysctl in sysctls {
+            if let Some(required) = required_namespace(&sysctl.name)
+                && namespaces.iter().any(|ns| ns.typ() == required)
+            {
+                new_sysctls.insert(sysctl.name.clone(), sysctl.value.clone());
+            }
+        }
+        linux.set_sysctl(Some(new_sysctls));
+        spec.set_linux(Some(linux));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn spec_with_sysctls(sysctls: &[(&str, &str)]) -> Spec {
+        let mut spec = Spec::default();
+        let mut linux = spec.linux().clone().unwrap();
+        linux.set_sysctl(Some(
+            sysctls
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        ));
+        spec.set_linux(Some(linux));
+        spec
+    }
+
+    fn sysctls(spec: &Spec) -> HashMap<String, String> {
+        spec.linux().as_ref().unwrap().sysctl().clone().unwrap()
+    }
+
+    #[test]
+    fn parses_namespaced_sysctl() {
+        let s = parse_sysctl("net.ipv4.ping_group_range=0 2147483647").unwrap();
+        assert_eq!(s.name, "net.ipv4.ping_group_range");
+        assert_eq!(s.value, "0 2147483647");
+        assert!(parse_sysctl("kernel.shmmax=1024").is_ok());
+        assert!(parse_sysctl("fs.mqueue.msg_max=16").is_ok());
+        assert!(parse_sysctl("kernel.domainname=example").is_ok());
+    }
+
+    #[test]
+    fn non_namespaced_sysctl_is_rejected() {
+        assert!(parse_sysctl("kernel.pid_max=4096").is_err());
+        assert!(parse_sysctl("vm.swappiness=0").is_err());
+    }
+
+    #[test]
+    fn malformed_sysctl_is_rejected() {
+        assert!(parse_sysctl("net.ipv4.ip_forward").is_err());
+        assert!(parse_sysctl("=1").is_err());
+        assert!(parse_sysctl("net.ipv4.ip_forward=").is_err());
+    }
+
+    #[test]
+    fn sets_and_overrides_sysctls() {
+        let mut spec = spec_with_sysctls(&[("net.ipv4.ip_forward", "1")]);
+        let injected = [
+            parse_sysctl("net.ipv4.ip_forward=0").unwrap(),
+            parse_sysctl("kernel.shmmax=1024").unwrap(),
+        ];
+        modify_sysctls(&mut spec, &injected, &SysctlPolicy::default()).unwrap();
+        let sysctls = sysctls(&spec);
+        assert_eq!(sysctls["net.ipv4.ip_forward"], "0");
+        assert_eq!(sysctls["kernel.shmmax"], "1024");
+    }
+
+    #[test]
+    fn skips_sysctls_without_namespace() {
+        let mut spec = spec_with_sysctls(&[]);
+        let mut linux = spec.linux().clone().unwrap();
+        let mut namespaces = linux.namespaces().clone().unwrap();
+        namespaces.retain(|ns| ns.typ() != LinuxNamespaceType::Network);
+        linux.set_namespaces(Some(namespaces));
+        spec.set_linux(Some(linux));
+        let injected = [parse_sysctl("net.ipv4.ip_forward=0").unwrap()];
+        modify_sysctls(&mut spec, &injected, &SysctlPolicy::default()).unwrap();
+        assert!(sysctls(&spec).is_empty());
+    }
+
+    #[test]
+    fn strips_denied_sysctls() {
+        let mut spec = spec_with_sysctls(&[
+            ("net.ipv4.ping_group_range", "0 0"),
+            ("kernel.shmmax", "1024"),
+            ("net.ipv4.ip_forward", "1"),
+        ]);
+        let policy = SysctlPolicy {
+            allow: vec![
+                String::from("net.ipv4.ping_group_range"),
+                String::from("kernel.*"),
+            ],
+            deny: vec![String::from("kernel.*")],
+            reject: false,
+        };
+        modify_sysctls(&mut spec, &[], &policy).unwrap();
+        let sysctls = sysctls(&spec);
+        assert_eq!(sysctls.len(), 1);
+        assert!(sysctls.contains_key("net.ipv4.ping_group_range"));
+    }
+
+    #[test]
+    fn rejects_denied_sysctls_in_reject_mode() {
+        let mut spec = spec_with_sysctls(&[("kernel.shmmax", "1024")]);
+        let policy = SysctlPolicy {
+            deny: vec![String::from("kernel.*")],
+            reject: true,
+            ..Default::default()
+        };
+        assert!(modify_sysctls(&mut spec, &[], &policy).is_err());
+    }
+
+    #[test]
+    fn injected_sysctls_bypass_policy() {
+        let mut spec = spec_with_sysctls(&[]);
+        let policy = SysctlPolicy {
+            deny: vec![String::from("kernel.*")],
+            reject: true,
+            ..Default::default()
+        };
+        let injected = [parse_sysctl("kernel.shmmax=1024").unwrap()];
+        modify_sysctls(&mut spec, &injected, &policy).unwrap();
+        assert_eq!(sysctls(&spec)["kernel.shmmax"], "1024");
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Result, bail};
+use oci_spec::runtime::Spec;
+
+/// Expands placeholders in `template` using values from the container config.
+///
+/// Supported placeholders:
+///
+/// - `{hostname}`: the container hostname
+/// - `{annotation:<key>}`: the value of the annotation `<key>`
+///
+/// It is an error for a placeholder to reference a value which is not present in the config.
+pub(crate) fn expand_template(spec: &Spec, template: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unterminated placeholder in template \"{template}\"");
+        };
+        let placeholder = &rest[start + 1..start + len];
+        expanded.push_str(&resolve_placeholder(spec, placeholder)?);
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn resolve_placeholder(spec: &Spec, placeholder: &str) -> Result<String> {
+    if placeholder == "hostname" {
+        return match spec.hostname() {
+            Some(hostname) if !hostname.is_empty() => Ok(hostname.clone()),
+            _ => bail!("container config has no hostname"),
+        };
+    }
+    if let Some(key) = placeholder.strip_prefix("annotation:") {
+        return match spec.annotations().as_ref().and_then(|a| a.get(key)) {
+            Some(value) => Ok(value.clone()),
+            None => bail!("container config has no annotation \"{key}\""),
+        };
+    }
+    bail!("unknown template placeholder \"{{{placeholder}}}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn spec() -> Spec {
+        let mut spec = Spec::default();
+        spec.set_hostname(Some(String::from("chal-1")));
+        spec.set_annotations(Some(HashMap::from([(
+            String::from("org.example.netns"),
+            String::from("blue"),
+        )])));
+        spec
+    }
+
+    #[test]
+    fn literal_template_is_unchanged() {
+        assert_eq!(expand_template(&spec(), "ctf").unwrap(), "ctf");
+    }
+
+    #[test]
+    fn expands_hostname() {
+        assert_eq!(
+            expand_template(&spec(), "ns-{hostname}").unwrap(),
+            "ns-chal-1"
+        );
+    }
+
+    #[test]
+    fn expands_annotation() {
+        assert_eq!(
+            expand_template(&spec(), "{annotation:org.example.netns}-{hostname}").unwrap(),
+            "blue-chal-1"
+        );
+    }
+
+    #[test]
+    fn missing_annotation_is_rejected() {
+        assert!(expand_template(&spec(), "{annotation:org.example.missing}").is_err());
+    }
+
+    #[test]
+    fn missing_hostname_is_rejected() {
+        let mut spec = spec();
+        spec.set_hostname(None);
+        assert!(expand_template(&spec, "{hostname}").is_err());
+    }
+
+    #[test]
+    fn unknown_placeholder_is_rejected() {
+        assert!(expand_template(&spec(), "{image}").is_err());
+    }
+
+    #[test]
+    fn unterminated_placeholder_is_rejected() {
+        assert!(expand_template(&spec(), "ns-{hostname").is_err());
+    }
+}
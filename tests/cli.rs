@@ -1,12 +1,30 @@
 //! CLI smoke tests for the oci-interceptor binary.
 //!
-//! These exercise clap-handled flags that short-circuit before any runtime call,
-//! so they do not require Docker or runc and always run as part of `cargo test`.
+//! These exercise clap-handled flags and subcommands that short-circuit before any runtime call, or
+//! forward to stub runtimes (`true` or shell scripts), so they do not require Docker or runc and
+//! always run as part of `cargo test`.
 
-use std::process::Command;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tempfile::TempDir;
 
 const BIN: &str = env!("CARGO_BIN_EXE_oci-interceptor");
 
+/// Creates a temporary bundle directory containing a minimal config, which is removed when
+/// dropped.
+fn bundle() -> TempDir {
+    let bundle = tempfile::tempdir().unwrap();
+    std::fs::write(
+        bundle.path().join("config.json"),
+        r#"{"ociVersion": "1.2.0", "hostname": "chal-1", "root": {"path": "rootfs"}, "process": {"cwd": "/", "args": ["sh"], "env": ["PATH=/bin"], "user": {"uid": 0, "gid": 0}}}"#,
+    )
+    .unwrap();
+    bundle
+}
+
 #[test]
 fn version_flag_prints_version() {
     let out = Command::new(BIN)
@@ -42,3 +60,509 @@ fn help_flag_prints_usage() {
         "help output missing expected flag, got: {stdout}"
     );
 }
+
+#[test]
+fn seccomp_generate_emits_profile_from_audit_log() {
+    let audit_log = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/seccomp_audit.log"
+    );
+    let out = Command::new(BIN)
+        .args([
+            "oi-seccomp-generate",
+            "--audit-log",
+            audit_log,
+            "--pid",
+            "4242",
+            "--pid",
+            "4243",
+        ])
+        .output()
+        .expect("failed to invoke oci-interceptor");
+    assert!(
+        out.status.success(),
+        "oi-seccomp-generate exited non-zero: {:?}\nstderr: {}",
+        out.status,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let profile: serde_json::Value =
+        serde_json::from_slice(&out.stdout).expect("profile is not valid JSON");
+    assert_eq!(profile["defaultAction"], "SCMP_ACT_ERRNO");
+    assert_eq!(
+        profile["architectures"],
+        serde_json::json!(["SCMP_ARCH_X86_64"])
+    );
+    assert_eq!(
+        profile["syscalls"],
+        serde_json::json!([{
+            "names": ["execve", "openat", "read"],
+            "action": "SCMP_ACT_ALLOW"
+        }])
+    );
+}
+
+#[test]
+fn hook_mode_records_container_pid() {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_dir = dir.path().join("pids");
+    let mut child = Command::new(BIN)
+        .args(["oi-hook", "record-pid", "--pid-dir"])
+        .arg(&pid_dir)
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("failed to invoke oci-interceptor");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            br#"{"ociVersion": "1.2.0", "id": "cid", "status": "created", "pid": 4242, "bundle": "/tmp/bundle"}"#,
+        )
+        .unwrap();
+    let status = child.wait().unwrap();
+    assert!(status.success(), "oi-hook exited non-zero: {status:?}");
+    assert_eq!(
+        std::fs::read_to_string(pid_dir.join("cid.pid")).unwrap(),
+        "4242\n"
+    );
+}
+
+#[test]
+fn recorded_annotations_are_idempotent() {
+    let dir = bundle();
+    let bundle = dir.path();
+    let config_path = bundle.join("config.json");
+    let intercept = || {
+        let status = Command::new(BIN)
+            .args([
+                "--oi-runtime-path",
+                "true",
+                "--oi-env",
+                "LANG=C.UTF-8",
+                "--oi-sysctl-deny",
+                "kernel.*",
+                "--oi-record-annotations",
+                "create",
+                "--bundle",
+            ])
+            .arg(bundle)
+            .arg("cid")
+            .status()
+            .expect("failed to invoke oci-interceptor");
+        assert!(status.success(), "interceptor exited non-zero: {status:?}");
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+        config
+    };
+
+    let first = intercept();
+    assert_eq!(
+        first["process"]["env"],
+        serde_json::json!(["PATH=/bin", "LANG=C.UTF-8"])
+    );
+    let annotations = &first["annotations"];
+    assert_eq!(
+        annotations["org.picoctf.oci-interceptor.version"],
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(
+        annotations["org.picoctf.oci-interceptor.modifications"],
+        "env"
+    );
+    assert_eq!(
+        annotations["org.picoctf.oci-interceptor.policy-hash"]
+            .as_str()
+            .unwrap()
+            .len(),
+        16
+    );
+    assert_eq!(intercept(), first);
+
+    // A config changed since it was intercepted is modified again
+    let mut changed = first.clone();
+    changed["process"]["env"] = serde_json::json!(["PATH=/bin"]);
+    std::fs::write(&config_path, changed.to_string()).unwrap();
+    assert_eq!(intercept(), first);
+}
+
+#[test]
+fn bundle_defaults_to_working_directory() {
+    let dir = bundle();
+    let bundle = dir.path();
+    let config_path = bundle.join("config.json");
+    let status = Command::new(BIN)
+        .args([
+            "--oi-runtime-path",
+            "true",
+            "--oi-env",
+            "LANG=C.UTF-8",
+            "run",
+            "--detach",
+            "cid",
+        ])
+        .current_dir(bundle)
+        .status()
+        .expect("failed to invoke oci-interceptor");
+    assert!(status.success(), "interceptor exited non-zero: {status:?}");
+    let config: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    assert_eq!(
+        config["process"]["env"],
+        serde_json::json!(["PATH=/bin", "LANG=C.UTF-8"])
+    );
+}
+
+/// Writes an executable shell script to use in place of a runtime.
+fn runtime_script(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+    let path = dir.join(format!("{name}.sh"));
+    std::fs::write(&path, format!("#!/bin/sh\n{contents}\n")).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn runtime_replaces_interceptor_process() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = runtime_script(dir.path(), "exec", "echo $$");
+    let child = Command::new(BIN)
+        .arg("--oi-runtime-path")
+        .arg(&runtime)
+        .args(["state", "cid"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to invoke oci-interceptor");
+    let pid = child.id();
+    let out = child.wait_with_output().unwrap();
+    assert!(
+        out.status.success(),
+        "runtime exited non-zero: {:?}",
+        out.status
+    );
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), pid.to_string());
+}
+
+#[test]
+fn signal_killed_runtime_exits_with_128_plus_signo() {
+    // Releasing cpusets after delete requires waiting for the runtime
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = runtime_script(dir.path(), "signal", "kill -TERM $$");
+    let state = dir.path().join("cpusets.json");
+    let status = Command::new(BIN)
+        .args([
+            "--oi-cpuset-pool",
+            "0",
+            "--oi-cpuset-cpus",
+            "1",
+            "--oi-cpuset-state",
+        ])
+        .arg(&state)
+        .arg("--oi-runtime-path")
+        .arg(&runtime)
+        .args(["delete", "cid"])
+        .status()
+        .expect("failed to invoke oci-interceptor");
+    assert_eq!(status.code(), Some(143));
+}
+
+#[test]
+fn routed_runtime_is_remembered_until_delete() {
+    let dir = bundle();
+    let bundle = dir.path();
+    let calls = bundle.join("calls.log");
+    let state = bundle.join("runtimes.json");
+    let log_call = |name: &str| format!("echo \"{name} $1\" >> {}", calls.display());
+    let default = runtime_script(bundle, "default", &log_call("default"));
+    let sandbox = runtime_script(bundle, "sandbox", &log_call("sandbox"));
+    let intercept = |args: &[&str]| {
+        let status = Command::new(BIN)
+            .arg("--oi-runtime-path")
+            .arg(&default)
+            .arg("--oi-runtime")
+            .arg(format!("{}@hostname=chal-*", sandbox.display()))
+            .arg("--oi-runtime-state")
+            .arg(&state)
+            .args(args)
+            .status()
+            .expect("failed to invoke oci-interceptor");
+        assert!(status.success(), "interceptor exited non-zero: {status:?}");
+    };
+    intercept(&["create", "--bundle", bundle.to_str().unwrap(), "cid"]);
+    intercept(&["start", "cid"]);
+    intercept(&["start", "other"]);
+    intercept(&["delete", "cid"]);
+    intercept(&["state", "cid"]);
+    assert_eq!(
+        std::fs::read_to_string(&calls).unwrap(),
+        "sandbox create\nsandbox start\ndefault start\nsandbox delete\ndefault state\n"
+    );
+}
+
+#[test]
+fn duplicate_create_keeps_existing_route() {
+    let dir = bundle();
+    let bundle = dir.path();
+    let calls = bundle.join("calls.log");
+    let created = bundle.join("created");
+    let state = bundle.join("runtimes.json");
+    let default = runtime_script(
+        bundle,
+        "default",
+        &format!("echo \"default $1\" >> {}", calls.display()),
+    );
+    // Rejects a second container with the same ID
+    let sandbox = runtime_script(
+        bundle,
+        "sandbox",
+        &format!(
+            "echo \"sandbox $1\" >> {}\nif [ \"$1\" = create ]; then [ ! -e {created} ] || exit 1; touch {created}; fi",
+            calls.display(),
+            created = created.display()
+        ),
+    );
+    let intercept = |args: &[&str]| {
+        Command::new(BIN)
+            .arg("--oi-runtime-path")
+            .arg(&default)
+            .arg("--oi-runtime")
+            .arg(format!("{}@hostname=chal-*", sandbox.display()))
+            .arg("--oi-runtime-state")
+            .arg(&state)
+            .args(args)
+            .status()
+            .expect("failed to invoke oci-interceptor")
+    };
+    let create = ["create", "--bundle", bundle.to_str().unwrap(), "cid"];
+    assert!(intercept(&create).success());
+    std::fs::write(
+        bundle.join("config.json"),
+        r#"{"ociVersion": "1.2.0", "hostname": "web", "root": {"path": "rootfs"}}"#,
+    )
+    .unwrap();
+    assert!(!intercept(&create).success());
+    assert!(intercept(&["start", "cid"]).success());
+    assert_eq!(
+        std::fs::read_to_string(&calls).unwrap(),
+        "sandbox create\nsandbox create\nsandbox start\n"
+    );
+}
+
+#[test]
+fn errors_are_written_to_runtime_log() {
+    let dir = bundle();
+    let bundle = dir.path();
+    std::fs::write(bundle.join("config.json"), "not json").unwrap();
+    let log = bundle.join("log.json");
+    let out = Command::new(BIN)
+        .args(["--oi-runtime-path", "true", "--log"])
+        .arg(&log)
+        .args(["--log-format", "json", "create", "--bundle"])
+        .arg(bundle)
+        .arg("cid")
+        .output()
+        .expect("failed to invoke oci-interceptor");
+    assert!(!out.status.success(), "interceptor exited zero");
+    let log = std::fs::read_to_string(&log).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+    assert_eq!(entry["level"], "error");
+    assert!(
+        entry["msg"]
+            .as_str()
+            .unwrap()
+            .starts_with("oci-interceptor: "),
+        "unexpected log entry: {log}"
+    );
+    assert!(entry["time"].as_str().unwrap().ends_with('Z'));
+}
+
+#[test]
+fn features_include_interceptor_section() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = runtime_script(
+        dir.path(),
+        "features",
+        r#"echo '{"ociVersionMin": "1.0.0", "annotations": {"org.opencontainers.runc.version": "1.1.12"}}'"#,
+    );
+    let out = Command::new(BIN)
+        .arg("--oi-runtime-path")
+        .arg(&runtime)
+        .args(["--oi-annotation-allow", "skip", "features"])
+        .output()
+        .expect("failed to invoke oci-interceptor");
+    assert!(
+        out.status.success(),
+        "interceptor exited non-zero: {:?}",
+        out.status
+    );
+    let features: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(features["ociVersionMin"], "1.0.0");
+    assert_eq!(
+        features["annotations"]["org.picoctf.oci-interceptor.version"],
+        env!("CARGO_PKG_VERSION")
+    );
+    let interceptor = &features["org.picoctf.oci-interceptor"];
+    assert_eq!(
+        interceptor["annotations"],
+        serde_json::json!({"org.picoctf.oci-interceptor.skip": ["*"]})
+    );
+    assert_eq!(
+        interceptor["policies"],
+        serde_json::json!(["--oi-annotation-allow"])
+    );
+}
+
+#[test]
+fn signals_are_forwarded_to_runtime() {
+    // Releasing cpusets after delete requires waiting for the runtime
+    let dir = tempfile::tempdir().unwrap();
+    let ready = dir.path().join("ready");
+    let runtime = runtime_script(
+        dir.path(),
+        "forward",
+        &format!(
+            "trap 'exit 42' TERM\ntouch {}\nwhile true; do sleep 0.1; done",
+            ready.display()
+        ),
+    );
+    let state = dir.path().join("cpusets.json");
+    let mut child = Command::new(BIN)
+        .args([
+            "--oi-cpuset-pool",
+            "0",
+            "--oi-cpuset-cpus",
+            "1",
+            "--oi-cpuset-state",
+        ])
+        .arg(&state)
+        .arg("--oi-runtime-path")
+        .arg(&runtime)
+        .args(["delete", "cid"])
+        .spawn()
+        .expect("failed to invoke oci-interceptor");
+    while !ready.exists() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(child.wait().unwrap().code(), Some(42));
+}
+
+#[test]
+fn cpusets_are_released_if_container_is_not_created() {
+    let dir = bundle();
+    let bundle = dir.path();
+    std::fs::write(
+        bundle.join("config.json"),
+        r#"{"ociVersion": "1.2.0", "root": {"path": "rootfs"}, "linux": {}}"#,
+    )
+    .unwrap();
+    let state = bundle.join("cpusets.json");
+    let status = Command::new(BIN)
+        .args([
+            "--oi-cpuset-pool",
+            "0",
+            "--oi-cpuset-cpus",
+            "1",
+            "--oi-cpuset-state",
+        ])
+        .arg(&state)
+        .args([
+            "--oi-runtime-path",
+            "/nonexistent/runtime",
+            "create",
+            "--bundle",
+        ])
+        .arg(bundle)
+        .arg("cid")
+        .status()
+        .expect("failed to invoke oci-interceptor");
+    assert!(!status.success(), "interceptor exited zero");
+    let allocations: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&state).unwrap()).unwrap();
+    assert_eq!(allocations, serde_json::json!({}));
+}
+
+#[test]
+fn duplicate_create_keeps_existing_cpuset() {
+    let dir = bundle();
+    let bundle = dir.path();
+    std::fs::write(
+        bundle.join("config.json"),
+        r#"{"ociVersion": "1.2.0", "root": {"path": "rootfs"}, "linux": {}}"#,
+    )
+    .unwrap();
+    let state = bundle.join("cpusets.json");
+    let create = |runtime: &str| {
+        Command::new(BIN)
+            .args(["--oi-cpuset-pool", "0", "--oi-cpuset-cpus", "1"])
+            .arg("--oi-cpuset-state")
+            .arg(&state)
+            .args(["--oi-runtime-path", runtime, "create", "--bundle"])
+            .arg(bundle)
+            .arg("cid")
+            .status()
+            .expect("failed to invoke oci-interceptor")
+    };
+    assert!(create("true").success());
+
+    // The runtime rejects a second container with the same ID
+    assert!(!create("false").success());
+    let allocations: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&state).unwrap()).unwrap();
+    assert_eq!(allocations["cid"]["cpus"], serde_json::json!([0]));
+}
+
+#[test]
+fn exec_uses_alternate_config() {
+    let dir = bundle();
+    let bundle = dir.path();
+    std::fs::write(
+        bundle.join("alt.json"),
+        r#"{"ociVersion": "1.2.0", "root": {"path": "rootfs"}, "process": {"cwd": "/", "args": ["sh"], "env": ["PATH=/bin"], "user": {"uid": 0, "gid": 0}, "noNewPrivileges": true}}"#,
+    )
+    .unwrap();
+    let status = Command::new(BIN)
+        .args(["--oi-runtime-path", "true", "--oi-env", "LANG=C.UTF-8"])
+        .args(["create", "--config", "alt.json", "--bundle"])
+        .arg(bundle)
+        .arg("cid")
+        .status()
+        .expect("failed to invoke oci-interceptor");
+    assert!(status.success(), "interceptor exited non-zero: {status:?}");
+    let config: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(bundle.join("alt.json")).unwrap()).unwrap();
+    let annotations = &config["annotations"];
+    assert_eq!(
+        annotations["org.picoctf.oci-interceptor.config"],
+        bundle.join("alt.json").to_str().unwrap()
+    );
+
+    // The runtime reports the bundle and annotations of the container
+    let state = serde_json::json!({"bundle": bundle, "annotations": annotations});
+    let runtime = runtime_script(
+        bundle,
+        "alt-config",
+        &format!("[ \"$1\" = state ] && echo '{state}'\nexit 0"),
+    );
+    let process_path = bundle.join("process.json");
+    std::fs::write(
+        &process_path,
+        r#"{"cwd": "/", "args": ["sh"], "user": {"uid": 0, "gid": 0}}"#,
+    )
+    .unwrap();
+    let status = Command::new(BIN)
+        .arg("--oi-runtime-path")
+        .arg(&runtime)
+        .args(["exec", "--process"])
+        .arg(&process_path)
+        .arg("cid")
+        .status()
+        .expect("failed to invoke oci-interceptor");
+    assert!(status.success(), "interceptor exited non-zero: {status:?}");
+    let process: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&process_path).unwrap()).unwrap();
+    assert_eq!(process["noNewPrivileges"], true);
+}